@@ -1,9 +1,11 @@
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
+use std::ops::Bound::{Excluded, Unbounded};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Side {
     Buy,
     Sell,
@@ -16,271 +18,4030 @@ impl fmt::Display for Side {
         }
     }
 }
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OrderType {
+    Limit,
+    Market,
+    // Parked in the stop book until the last trade price reaches the trigger,
+    // then entered as a market or limit order respectively.
+    Stop(Decimal),
+    StopLimit(Decimal),
+}
+
+type Timestamp = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeInForce {
+    GoodTillCancel,
+    ImmediateOrCancel,
+    FillOrKill,
+    Day,
+    GoodTillDate(Timestamp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PostOnly {
+    Reject,
+    // Slide the price one tick behind the opposite best instead of crossing.
+    Reprice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PostOnlyAction {
+    Posted,
+    Repriced(Decimal),
+    Rejected,
+}
+
+// Distance a trailing stop keeps from the best price traded since it was entered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trail {
+    Amount(Decimal),
+    Percent(Decimal),
+}
+
+impl Trail {
+    fn trigger_price(&self, side: &Side, price: Decimal) -> Decimal {
+        let distance = match self {
+            Trail::Amount(amount) => *amount,
+            Trail::Percent(percent) => price * percent / dec!(100),
+        };
+        match side {
+            Side::Buy => price + distance,
+            Side::Sell => price - distance,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PegReference {
+    // Same-side best price.
+    Primary,
+    Midpoint,
+    // Opposite best price.
+    Market,
+}
+
+// Pegged orders are priced at `reference + offset` and follow the best bid and
+// offer of the non-pegged orders in the book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Peg {
+    reference: PegReference,
+    offset: Decimal,
+}
+
+// What happens when an incoming order would trade against a resting order of
+// the same owner. The policy of the incoming order applies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SelfTradePrevention {
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    // Take the quantity that would have traded off both orders without a fill.
+    Decrement,
+}
+
 #[derive(Debug, Clone)]
 struct Order {
+    // Exchange order id, assigned by the book on entry.
     id: i64,
+    // The id the client sent, kept for correlation.
+    client_id: i64,
     price: Decimal,
-    quantity: i64,
+    quantity: Decimal,
     side: Side,
+    order_type: OrderType,
+    time_in_force: TimeInForce,
+    post_only: Option<PostOnly>,
+    // Iceberg peak: only this much of the order is displayed at a time, the
+    // rest waits in `reserve` and refills the peak once it has traded away.
+    display_quantity: Option<Decimal>,
+    reserve: Decimal,
+    // Hidden orders are never displayed and trade after the displayed orders at their price.
+    hidden: bool,
+    peg: Option<Peg>,
+    // Makes a stop trail the market: its trigger only ever moves towards the price.
+    trail: Option<Trail>,
+    // Resting all-or-none orders only trade against an order that takes them in full.
+    all_or_none: bool,
+    // An incoming order with a minimum quantity trades only if at least that much executes.
+    min_quantity: Option<Decimal>,
+    // Entered by the lead market maker, which some allocation steps favour.
+    market_maker: bool,
+    // Firm or trader the order belongs to; orders without an owner never count as self-trades.
+    owner: Option<i64>,
+    self_trade_prevention: SelfTradePrevention,
 }
 
-#[derive(Debug, Clone)]
-struct Fill {
-    matched_id: i64,
-    volume: i64,
-    price: Decimal,
-    taker_id: i64,
-    maker_id: i64,
+impl Order {
+    fn limit(client_id: i64, side: Side, price: Decimal, quantity: Decimal) -> Self {
+        Order {
+            id: 0,
+            client_id,
+            price,
+            quantity,
+            side,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            post_only: None,
+            display_quantity: None,
+            reserve: Decimal::ZERO,
+            hidden: false,
+            peg: None,
+            trail: None,
+            all_or_none: false,
+            min_quantity: None,
+            market_maker: false,
+            owner: None,
+            self_trade_prevention: SelfTradePrevention::CancelNewest,
+        }
+    }
+
+    // Market orders carry no limit price; `price` is ignored while matching.
+    fn market(client_id: i64, side: Side, quantity: Decimal) -> Self {
+        Order {
+            order_type: OrderType::Market,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            ..Order::limit(client_id, side, Decimal::ZERO, quantity)
+        }
+    }
+
+    fn stop(client_id: i64, side: Side, trigger_price: Decimal, quantity: Decimal) -> Self {
+        Order {
+            order_type: OrderType::Stop(trigger_price),
+            ..Order::limit(client_id, side, Decimal::ZERO, quantity)
+        }
+    }
+
+    fn stop_limit(
+        client_id: i64,
+        side: Side,
+        trigger_price: Decimal,
+        price: Decimal,
+        quantity: Decimal,
+    ) -> Self {
+        Order {
+            order_type: OrderType::StopLimit(trigger_price),
+            ..Order::limit(client_id, side, price, quantity)
+        }
+    }
+
+    fn trailing_stop(client_id: i64, side: Side, trail: Trail, quantity: Decimal) -> Self {
+        Order::stop(client_id, side, Decimal::ZERO, quantity).with_trail(trail)
+    }
+
+    // The book sets the price on entry and whenever the reference moves.
+    fn pegged(
+        client_id: i64,
+        side: Side,
+        reference: PegReference,
+        offset: Decimal,
+        quantity: Decimal,
+    ) -> Self {
+        Order {
+            peg: Some(Peg { reference, offset }),
+            ..Order::limit(client_id, side, Decimal::ZERO, quantity)
+        }
+    }
+
+    // The trigger price is set from the last trade price on entry.
+    fn with_trail(mut self, trail: Trail) -> Self {
+        self.trail = Some(trail);
+        self
+    }
+
+    fn with_all_or_none(mut self) -> Self {
+        self.all_or_none = true;
+        self
+    }
+
+    fn with_min_quantity(mut self, min_quantity: Decimal) -> Self {
+        self.min_quantity = Some(min_quantity);
+        self
+    }
+
+    fn with_market_maker(mut self) -> Self {
+        self.market_maker = true;
+        self
+    }
+
+    fn with_owner(mut self, owner: i64) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
+    fn with_self_trade_prevention(mut self, self_trade_prevention: SelfTradePrevention) -> Self {
+        self.self_trade_prevention = self_trade_prevention;
+        self
+    }
+
+    fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    fn with_post_only(mut self, post_only: PostOnly) -> Self {
+        self.post_only = Some(post_only);
+        self
+    }
+
+    fn with_display_quantity(mut self, display_quantity: Decimal) -> Self {
+        self.display_quantity = Some(display_quantity);
+        self
+    }
+
+    fn with_hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    // Quantity displayed plus any hidden iceberg reserve.
+    fn total_quantity(&self) -> Decimal {
+        self.quantity + self.reserve
+    }
+
+    // Refills the displayed quantity of an iceberg from its reserve.
+    fn show_peak(&mut self) {
+        if let Some(peak) = self.display_quantity {
+            let total = self.total_quantity();
+            self.quantity = total.min(peak);
+            self.reserve = total - self.quantity;
+        }
+    }
+
+    // Whether any residual left after matching may be placed on the book.
+    fn rests(&self) -> bool {
+        self.order_type == OrderType::Limit
+            && matches!(
+                self.time_in_force,
+                TimeInForce::GoodTillCancel | TimeInForce::Day | TimeInForce::GoodTillDate(_)
+            )
+    }
+
+    fn is_expired(&self, now: Timestamp) -> bool {
+        matches!(self.time_in_force, TimeInForce::GoodTillDate(expiry) if expiry <= now)
+    }
+
+    fn crosses(&self, price: Decimal) -> bool {
+        match self.order_type {
+            OrderType::Market | OrderType::Stop(_) => true,
+            OrderType::Limit | OrderType::StopLimit(_) => match self.side {
+                Side::Buy => self.price >= price,
+                Side::Sell => self.price <= price,
+            },
+        }
+    }
+
+    fn self_trades_with(&self, other: &Order) -> bool {
+        self.owner.is_some() && self.owner == other.owner
+    }
+
+    fn trigger_price(&self) -> Option<Decimal> {
+        match self.order_type {
+            OrderType::Stop(trigger_price) | OrderType::StopLimit(trigger_price) => {
+                Some(trigger_price)
+            }
+            OrderType::Limit | OrderType::Market => None,
+        }
+    }
+
+    fn set_trigger_price(&mut self, trigger_price: Decimal) {
+        self.order_type = match self.order_type {
+            OrderType::Stop(_) => OrderType::Stop(trigger_price),
+            OrderType::StopLimit(_) => OrderType::StopLimit(trigger_price),
+            order_type => order_type,
+        };
+    }
+
+    // Turns a triggered stop into the order it stands for.
+    fn activate(mut self) -> Self {
+        self.order_type = match self.order_type {
+            OrderType::Stop(_) => OrderType::Market,
+            OrderType::StopLimit(_) => OrderType::Limit,
+            order_type => order_type,
+        };
+        self
+    }
 }
 
-#[derive(Debug, Clone)]
-struct OrderBook {
-    bids: BTreeMap<Decimal, Vec<Order>>,
-    asks: BTreeMap<Decimal, Vec<Order>>,
-    orders: HashMap<i64, Order>,
-    match_id: i64,
+// Stop orders waiting for their trigger, keyed by trigger price. Buy stops fire
+// when the last trade is at or above the trigger, sell stops at or below.
+#[derive(Debug, Clone, Default)]
+struct StopBook {
+    buys: BTreeMap<Decimal, Vec<Order>>,
+    sells: BTreeMap<Decimal, Vec<Order>>,
+    // Where each stop waits, by order id.
+    index: HashMap<i64, Location>,
+    // Ids of the trailing stops, the only ones a trade can move.
+    trailing: BTreeSet<i64>,
 }
 
-impl OrderBook {
-    fn new() -> Self {
-        OrderBook {
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
-            orders: HashMap::new(),
-            match_id: 0,
+impl StopBook {
+    fn insert(&mut self, order: Order) {
+        let trigger_price = order.trigger_price().unwrap();
+        self.index.insert(
+            order.id,
+            Location {
+                side: order.side,
+                price: trigger_price,
+            },
+        );
+        if order.trail.is_some() {
+            self.trailing.insert(order.id);
         }
+        self.side_mut(order.side)
+            .entry(trigger_price)
+            .or_default()
+            .push(order);
     }
 
-    fn print_book(&self) {
-        println!("## Orderbook");
-        println!("{:<8} {:<8} {:<8} {:<8}", "ID", "Side", "Volume", "Price");
+    fn remove(&mut self, id: i64) -> Option<Order> {
+        let Location { side, price } = self.index.remove(&id)?;
+        self.trailing.remove(&id);
+        let stops = self.side_mut(side);
+        let orders = stops.get_mut(&price).unwrap();
+        let pos = orders.iter().position(|o| o.id == id).unwrap();
+        let order = orders.remove(pos);
+        if orders.is_empty() {
+            stops.remove(&price);
+        }
+        Some(order)
+    }
 
-        for (price, orders) in self.asks.iter().rev() {
-            for order in orders {
-                println!(
-                    "{:<8} {:<8} {:<8} {:<8}",
-                    order.id, order.side, order.quantity, price
-                );
+    fn get(&self, id: i64) -> Option<&Order> {
+        let Location { side, price } = self.index.get(&id)?;
+        let stops = match side {
+            Side::Buy => &self.buys,
+            Side::Sell => &self.sells,
+        };
+        stops[price].iter().find(|o| o.id == id)
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Decimal, Vec<Order>> {
+        match side {
+            Side::Buy => &mut self.buys,
+            Side::Sell => &mut self.sells,
+        }
+    }
+
+    fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.buys.values().chain(self.sells.values()).flatten()
+    }
+
+    // Tightens the trigger of every trailing stop that `price` moved in favour
+    // of; moved stops join the back of their new trigger price in id order.
+    fn trail(&mut self, price: Decimal) {
+        if self.trailing.is_empty() {
+            return;
+        }
+        let trailing: Vec<i64> = self.trailing.iter().copied().collect();
+        for id in trailing {
+            let order = self.get(id).unwrap();
+            let trigger_price = order.trigger_price().unwrap();
+            let new_trigger_price = order.trail.unwrap().trigger_price(&order.side, price);
+            let tighter = match order.side {
+                Side::Buy => new_trigger_price < trigger_price,
+                Side::Sell => new_trigger_price > trigger_price,
+            };
+            if tighter {
+                let mut order = self.remove(id).unwrap();
+                order.set_trigger_price(new_trigger_price);
+                self.insert(order);
             }
         }
+    }
+
+    // Next stop to fire at `last_price`: the trigger nearest to the market
+    // first, earliest arrival first within a trigger price.
+    fn pop_triggered(&mut self, last_price: Decimal) -> Option<Order> {
+        let (stops, trigger_price) = if let Some(&price) = self
+            .buys
+            .keys()
+            .next()
+            .filter(|&&price| price <= last_price)
+        {
+            (&mut self.buys, price)
+        } else if let Some(&price) = self
+            .sells
+            .keys()
+            .next_back()
+            .filter(|&&price| price >= last_price)
+        {
+            (&mut self.sells, price)
+        } else {
+            return None;
+        };
+
+        let orders = stops.get_mut(&trigger_price).unwrap();
+        let order = orders.remove(0);
+        if orders.is_empty() {
+            stops.remove(&trigger_price);
+        }
+        self.index.remove(&order.id);
+        self.trailing.remove(&order.id);
+        Some(order)
+    }
+}
 
-        println!("{:-<32}", "");
+#[derive(Debug, Clone)]
+struct Node {
+    order: Order,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
 
-        for (price, orders) in self.bids.iter().rev() {
-            for order in orders {
-                println!(
-                    "{:<8} {:<8} {:<8} {:<8}",
-                    order.id, order.side, order.quantity, price
-                );
+// Orders in time priority, doubly linked through a slab so that any order can
+// be found and unlinked in O(1) by id. Freed slots are reused.
+#[derive(Debug, Clone, Default)]
+struct OrderQueue {
+    slots: Vec<Option<Node>>,
+    free: Vec<usize>,
+    index: HashMap<i64, usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+impl OrderQueue {
+    fn push_back(&mut self, order: Order) {
+        let id = order.id;
+        let node = Node {
+            order,
+            prev: self.tail,
+            next: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.slots.push(Some(node));
+                self.slots.len() - 1
             }
+        };
+        match self.tail {
+            Some(tail) => self.node_mut(tail).next = Some(slot),
+            None => self.head = Some(slot),
         }
+        self.tail = Some(slot);
+        self.index.insert(id, slot);
     }
 
-    fn add_order(&mut self, mut order: Order) -> Vec<Fill> {
-        let mut fills = Vec::new();
+    fn remove(&mut self, id: i64) -> Option<Order> {
+        let slot = self.index.remove(&id)?;
+        let node = self.slots[slot].take().unwrap();
+        self.free.push(slot);
+        match node.prev {
+            Some(prev) => self.node_mut(prev).next = node.next,
+            None => self.head = node.next,
+        }
+        match node.next {
+            Some(next) => self.node_mut(next).prev = node.prev,
+            None => self.tail = node.prev,
+        }
+        Some(node.order)
+    }
 
-        if order.side == Side::Buy {
-            while order.quantity > 0 {
-                if let Some((&ask_price, ask_orders)) = self.asks.iter_mut().next() {
-                    if order.price >= ask_price {
-                        let mut remaining_quantity = order.quantity;
-                        let mut i = 0;
+    fn contains(&self, id: i64) -> bool {
+        self.index.contains_key(&id)
+    }
 
-                        while i < ask_orders.len() && remaining_quantity > 0 {
-                            let ask_order = &mut ask_orders[i];
-                            let trade_quantity = remaining_quantity.min(ask_order.quantity);
-                            self.match_id += 1;
-                            fills.push(Fill {
-                                matched_id: self.match_id,
-                                volume: trade_quantity,
-                                price: ask_price,
-                                taker_id: order.id,
-                                maker_id:ask_order.id
-                            });
+    fn get(&self, id: i64) -> Option<&Order> {
+        let slot = *self.index.get(&id)?;
+        Some(&self.node(slot).order)
+    }
 
-                            ask_order.quantity -= trade_quantity;
-                            remaining_quantity -= trade_quantity;
+    fn get_mut(&mut self, id: i64) -> Option<&mut Order> {
+        let slot = *self.index.get(&id)?;
+        Some(&mut self.node_mut(slot).order)
+    }
 
-                            if ask_order.quantity == 0 {
-                                self.orders.remove(&ask_order.id);
-                                ask_orders.remove(i);
-                            } else {
-                                i += 1;
-                            }
-                        }
+    fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
 
-                        order.quantity = remaining_quantity;
+    fn iter(&self) -> impl Iterator<Item = &Order> {
+        std::iter::successors(self.head, |&slot| self.node(slot).next)
+            .map(|slot| &self.node(slot).order)
+    }
 
-                        if ask_orders.is_empty() {
-                            self.asks.remove(&ask_price);
-                        }
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
+    // The orders an allocation over `quantity` has to look at. All-or-none
+    // orders can be passed over, so in time priority the walk stops once the
+    // other orders cover the quantity.
+    fn eligible(&self, quantity: Decimal, time_priority: bool) -> Vec<&Order> {
+        if !time_priority {
+            return self.iter().collect();
+        }
+        let mut covered = Decimal::ZERO;
+        self.iter()
+            .take_while(|order| {
+                let needed = covered < quantity;
+                if !order.all_or_none {
+                    covered += order.quantity;
                 }
-            }
+                needed
+            })
+            .collect()
+    }
 
-            if order.quantity > 0 {
-                self.orders.insert(order.id, order.clone());
-                self.bids
-                    .entry(order.price)
-                    .or_insert_with(Vec::new)
-                    .push(order);
-            }
+    fn node(&self, slot: usize) -> &Node {
+        self.slots[slot].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node {
+        self.slots[slot].as_mut().unwrap()
+    }
+}
+
+// Orders resting at one price. Displayed orders keep time priority among
+// themselves; hidden orders only trade once every displayed order is gone.
+#[derive(Debug, Clone, Default)]
+struct PriceLevel {
+    displayed: OrderQueue,
+    hidden: OrderQueue,
+}
+
+impl PriceLevel {
+    fn push(&mut self, order: Order) {
+        if order.hidden {
+            self.hidden.push_back(order);
         } else {
-            while order.quantity > 0 {
-                if let Some((&bid_price, bid_orders)) = self.bids.iter_mut().rev().next() {
-                    if order.price <= bid_price {
-                        let mut remaining_quantity = order.quantity;
-                        let mut i = 0;
-
-                        while i < bid_orders.len() && remaining_quantity > 0 {
-                            let bid_order = &mut bid_orders[i];
-                            let trade_quantity = remaining_quantity.min(bid_order.quantity);
-                            self.match_id += 1;
-                            fills.push(Fill {
-                                matched_id: self.match_id,
-                                volume: trade_quantity,
-                                price: bid_price,
-                                taker_id: order.id,
-                                maker_id:bid_order.id
-                            });
-
-                            bid_order.quantity -= trade_quantity;
-                            remaining_quantity -= trade_quantity;
-
-                            if bid_order.quantity == 0 {
-                                self.orders.remove(&bid_order.id);
-                                bid_orders.remove(i);
-                            } else {
-                                i += 1;
-                            }
-                        }
+            self.displayed.push_back(order);
+        }
+    }
 
-                        order.quantity = remaining_quantity;
+    fn is_empty(&self) -> bool {
+        self.displayed.is_empty() && self.hidden.is_empty()
+    }
 
-                        if bid_orders.is_empty() {
-                            self.bids.remove(&bid_price);
-                        }
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            }
+    fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.displayed.iter().chain(self.hidden.iter())
+    }
+
+    fn get(&self, id: i64) -> Option<&Order> {
+        self.displayed.get(id).or_else(|| self.hidden.get(id))
+    }
+
+    fn get_mut(&mut self, id: i64) -> Option<&mut Order> {
+        match self.displayed.contains(id) {
+            true => self.displayed.get_mut(id),
+            false => self.hidden.get_mut(id),
+        }
+    }
+
+    fn remove(&mut self, id: i64) -> Option<Order> {
+        self.displayed.remove(id).or_else(|| self.hidden.remove(id))
+    }
+
+    fn displayed_quantity(&self) -> Decimal {
+        self.displayed.iter().map(|o| o.quantity).sum()
+    }
+}
+
+// What an allocation strategy sees of an eligible resting order.
+#[derive(Debug, Clone, Copy)]
+struct Resting {
+    quantity: Decimal,
+    market_maker: bool,
+}
+
+// Shares an incoming quantity among the resting orders of one price level.
+// `resting` lists the eligible orders in time priority; the result pairs
+// indexes into `resting` with the quantity they trade, in the order the fills
+// are emitted. Shares are whole multiples of `lot_size`.
+trait AllocationStrategy: fmt::Debug {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)>;
+
+    // True when shares only ever go to the oldest orders, so matching can stop
+    // walking the queue once the incoming quantity is covered.
+    fn time_priority(&self) -> bool {
+        false
+    }
+}
+
+// Price-time priority: the oldest order is filled first.
+#[derive(Debug, Clone, Copy)]
+struct Fifo;
 
-            if order.quantity > 0 {
-                self.orders.insert(order.id, order.clone());
-                self.asks
-                    .entry(order.price)
-                    .or_insert_with(Vec::new)
-                    .push(order);
+impl AllocationStrategy for Fifo {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        _lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)> {
+        let mut remaining = quantity;
+        let mut allocations = Vec::new();
+        for (i, order) in resting.iter().enumerate() {
+            if remaining.is_zero() {
+                break;
             }
+            let allocation = remaining.min(order.quantity);
+            remaining -= allocation;
+            allocations.push((i, allocation));
         }
-        fills
+        allocations
     }
 
-    fn remove_order(&mut self, id: i64) -> Option<Order> {
-        if let Some(order) = self.orders.remove(&id) {
-            let book_side = match order.side {
-                Side::Buy => &mut self.bids,
-                Side::Sell => &mut self.asks,
-            };
+    fn time_priority(&self) -> bool {
+        true
+    }
+}
 
-            if let Some(orders) = book_side.get_mut(&order.price) {
-                if let Some(pos) = orders.iter().position(|o| o.id == order.id) {
-                    orders.remove(pos);
-                    if orders.is_empty() {
-                        book_side.remove(&order.price);
-                    }
-                    return Some(order);
+// Allocates in proportion to resting size, rounding down. Shares below the
+// minimum allocation are dropped and whatever is left over is handed out in
+// time priority.
+#[derive(Debug, Clone, Copy)]
+struct ProRata {
+    minimum_allocation: Decimal,
+}
+
+impl AllocationStrategy for ProRata {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)> {
+        let total: Decimal = resting.iter().map(|order| order.quantity).sum();
+        if quantity >= total {
+            return resting
+                .iter()
+                .map(|order| order.quantity)
+                .enumerate()
+                .collect();
+        }
+
+        let mut allocations: Vec<Decimal> = resting
+            .iter()
+            .map(|order| round_to_lot(quantity * order.quantity / total, lot_size))
+            .map(|share| {
+                if share >= self.minimum_allocation {
+                    share
+                } else {
+                    Decimal::ZERO
                 }
-            }
+            })
+            .collect();
+        let leftover = quantity - allocations.iter().sum::<Decimal>();
+        let capacity: Vec<Resting> = resting
+            .iter()
+            .zip(&allocations)
+            .map(|(order, allocated)| Resting {
+                quantity: order.quantity - allocated,
+                ..*order
+            })
+            .collect();
+        for (i, extra) in Fifo.allocate(leftover, lot_size, &capacity) {
+            allocations[i] += extra;
         }
-        return Option::None;
+
+        allocations
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, allocation)| allocation > Decimal::ZERO)
+            .collect()
     }
+}
 
-    fn update_order(&mut self, id: i64, price: Option<Decimal>, qty: Option<i64>) -> Vec<Fill> {
-        if let Some(mut order) = self.remove_order(id) {
-            if let Some(price) = price {
-                order.price = price;
-            }
+// The order at the front of the queue is served first, optionally capped.
+#[derive(Debug, Clone, Copy)]
+struct TopOrder {
+    max_quantity: Option<Decimal>,
+}
+
+impl AllocationStrategy for TopOrder {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        _lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)> {
+        let cap = self.max_quantity.unwrap_or(quantity);
+        match resting.first() {
+            Some(order) => vec![(0, quantity.min(cap).min(order.quantity))],
+            None => Vec::new(),
+        }
+    }
+}
+
+// Reserves a percentage of the incoming quantity, rounded down, for the lead
+// market maker's orders, filled among them in time priority.
+#[derive(Debug, Clone, Copy)]
+struct LeadMarketMaker {
+    percent: Decimal,
+}
+
+impl AllocationStrategy for LeadMarketMaker {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)> {
+        let share = round_to_lot(quantity * self.percent / dec!(100), lot_size);
+        let market_maker: Vec<Resting> = resting
+            .iter()
+            .map(|order| Resting {
+                quantity: if order.market_maker {
+                    order.quantity
+                } else {
+                    Decimal::ZERO
+                },
+                ..*order
+            })
+            .collect();
+        Fifo.allocate(share, lot_size, &market_maker)
+    }
+}
+
+// Runs its steps in order, each one sharing out what the previous steps left
+// of the incoming quantity among what the resting orders have left. Fills are
+// emitted step by step, so an order served by two steps trades twice. What no
+// step hands out does not trade at that price, so pipelines normally end with
+// `Fifo` or `ProRata`.
+#[derive(Debug, Default)]
+struct Pipeline {
+    steps: Vec<Box<dyn AllocationStrategy>>,
+}
+
+impl Pipeline {
+    fn then(mut self, step: impl AllocationStrategy + 'static) -> Self {
+        self.steps.push(Box::new(step));
+        self
+    }
+}
 
-            if let Some(qty) = qty  {
-                order.quantity = qty;
+impl AllocationStrategy for Pipeline {
+    fn allocate(
+        &self,
+        quantity: Decimal,
+        lot_size: Decimal,
+        resting: &[Resting],
+    ) -> Vec<(usize, Decimal)> {
+        let mut remaining = quantity;
+        let mut resting = resting.to_vec();
+        let mut allocations = Vec::new();
+        for step in &self.steps {
+            for (i, allocation) in step.allocate(remaining, lot_size, &resting) {
+                if allocation > Decimal::ZERO {
+                    remaining -= allocation;
+                    resting[i].quantity -= allocation;
+                    allocations.push((i, allocation));
+                }
             }
-           return  self.add_order(order); 
-        } else {
-            return Vec::new();
         }
+        allocations
     }
 }
 
-fn print_fills(fills: &[Fill]) {
-    println!("## Fills");
-    println!("{:<10} {:<8} {:<8}", "MatchedId", "Volume", "Price");
-    for fill in fills {
-        println!(
-            "{:<10} {:<8} {:<8}",
-            fill.matched_id, fill.volume, fill.price
-        );
+// One allocation pass over a queue as (queue index, quantity) pairs. All-or-none
+// orders that cannot be filled in full are left out and the rest reallocated.
+fn allocate_queue(
+    strategy: &dyn AllocationStrategy,
+    quantity: Decimal,
+    lot_size: Decimal,
+    queue: &[&Order],
+) -> Vec<(usize, Decimal)> {
+    let mut candidates: Vec<usize> = (0..queue.len())
+        .filter(|&i| !(queue[i].all_or_none && queue[i].total_quantity() > quantity))
+        .collect();
+    loop {
+        let resting: Vec<Resting> = candidates
+            .iter()
+            .map(|&i| Resting {
+                quantity: queue[i].quantity,
+                market_maker: queue[i].market_maker,
+            })
+            .collect();
+        let allocations: Vec<(usize, Decimal)> = strategy
+            .allocate(quantity, lot_size, &resting)
+            .into_iter()
+            .filter(|&(_, allocation)| allocation > Decimal::ZERO)
+            .map(|(candidate, allocation)| (candidates[candidate], allocation))
+            .collect();
+        let short: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let allocated: Decimal = allocations
+                    .iter()
+                    .filter(|&&(j, _)| j == i)
+                    .map(|&(_, allocation)| allocation)
+                    .sum();
+                queue[i].all_or_none
+                    && allocated > Decimal::ZERO
+                    && allocated < queue[i].total_quantity()
+            })
+            .collect();
+        if short.is_empty() {
+            return allocations;
+        }
+        candidates.retain(|i| !short.contains(i));
     }
-    println!()
 }
 
-fn main() {
-    let mut order_book = OrderBook::new();
+#[derive(Debug, Clone)]
+struct Fill {
+    matched_id: i64,
+    volume: Decimal,
+    price: Decimal,
+    taker_id: i64,
+    maker_id: i64,
+}
 
-    let order1 = Order {
-        id: 1,
-        price: dec!(100.0),
-        quantity: 10,
-        side: Side::Buy,
-    };
-    let order2 = Order {
-        id: 2,
-        price: dec!(100.0),
-        quantity: 5,
-        side: Side::Buy,
-    };
-    let order3 = Order {
-        id: 3,
-        price: dec!(101.0),
-        quantity: 7,
-        side: Side::Buy,
-    };
+#[derive(Debug, Clone, Default)]
+struct AddOrderResult {
+    fills: Vec<Fill>,
+    // Quantity that neither traded nor rested, e.g. the residual of a market order.
+    cancelled_quantity: Decimal,
+    post_only_action: Option<PostOnlyAction>,
+    reject_reason: Option<RejectReason>,
+    residual: Option<Order>,
+    // Quantity that did not trade because of self-trade prevention, and the
+    // orders it cancelled or decremented to nothing, which may include this one.
+    self_trade_prevented: Decimal,
+    self_trade_cancelled: Vec<i64>,
+    order_id: i64,
+    client_id: i64,
+    // Orders activated by this order's fills, i.e. triggered stops and bracket
+    // exit legs, including cascades.
+    triggered: Vec<AddOrderResult>,
+}
+
+impl AddOrderResult {
+    // This result followed by the results of everything it triggered.
+    fn flatten(&self) -> Vec<&AddOrderResult> {
+        let mut results = vec![self];
+        for triggered in &self.triggered {
+            results.extend(triggered.flatten());
+        }
+        results
+    }
+}
 
-    order_book.add_order(order1);
-    order_book.add_order(order2);
-    order_book.add_order(order3);
+// Cancelling a partly filled bracket entry activates its exit legs, which
+// can trade straight away.
+#[derive(Debug)]
+struct CancelResult {
+    order: Order,
+    activated: Vec<AddOrderResult>,
+}
 
-    let order4 = Order {
-        id: 4,
-        price: dec!(99.0),
-        quantity: 18,
-        side: Side::Sell,
-    };
+#[derive(Debug, Default)]
+struct ExpiryResult {
+    expired: Vec<Order>,
+    activated: Vec<AddOrderResult>,
+}
 
-    // We have 
-    let fills = order_book.add_order(order4);
+#[derive(Debug, Clone, Default)]
+struct AmendResult {
+    // False when the amend sent the order to the back of its queue.
+    priority_kept: bool,
+    fills: Vec<Fill>,
+}
 
-    print_fills(&fills);
-    order_book.print_book();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+    Rejected,
+    // Amended and still working.
+    Replaced,
+}
 
+impl OrderStatus {
+    fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled
+                | OrderStatus::Cancelled
+                | OrderStatus::Expired
+                | OrderStatus::Rejected
+        )
+    }
+}
 
-    let order5 = Order {
-        id: 1,
-        price: dec!(100.0),
-        quantity: 10,
-        side: Side::Buy,
-    };
+// The lifecycle of an order the book gave an id to. Requests turned away with
+// an error never get one.
+#[derive(Debug, Clone)]
+struct OrderRecord {
+    client_id: i64,
+    owner: Option<i64>,
+    status: OrderStatus,
+    original_quantity: Decimal,
+    // Read from the live order when queried; zero once the order is done.
+    remaining_quantity: Decimal,
+    filled_quantity: Decimal,
+    // Sum of volume times price over the fills.
+    notional: Decimal,
+    created: Timestamp,
+    updated: Timestamp,
+}
 
-    // Update order loses its priority
-    order_book.add_order(order5);
-    order_book.update_order(2, Option::None, Some(87));
+impl OrderRecord {
+    fn average_price(&self) -> Option<Decimal> {
+        (self.filled_quantity > Decimal::ZERO).then(|| self.notional / self.filled_quantity)
+    }
+}
 
-    order_book.print_book();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionState {
+    // Limit orders are collected for the opening auction, nothing trades.
+    PreOpen,
+    Auction,
+    Continuous,
+    // Only cancels are accepted.
+    Halted,
+    Closed,
+}
+
+impl SessionState {
+    fn can_transition_to(self, to: SessionState) -> bool {
+        use SessionState::*;
+        matches!(
+            (self, to),
+            (PreOpen, Auction | Continuous | Halted | Closed)
+                | (Auction, Continuous | Halted | Closed)
+                | (Continuous, Auction | Halted | Closed)
+                | (Halted, Auction | Continuous | Closed)
+                | (Closed, PreOpen)
+        )
+    }
+
+    fn allows(self, order: &Order) -> bool {
+        let immediate = matches!(
+            order.time_in_force,
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+        );
+        match self {
+            SessionState::PreOpen => order.order_type != OrderType::Market && !immediate,
+            // Market orders wait for the uncross in an auction, so only IOC and FOK are turned away.
+            SessionState::Auction => order.order_type == OrderType::Market || !immediate,
+            SessionState::Continuous => true,
+            SessionState::Halted | SessionState::Closed => false,
+        }
+    }
+}
+
+// Limits on how far a single order may move the price, in percent: the static
+// collar around the reference price and the dynamic band around the last trade.
+#[derive(Debug, Clone, Copy, Default)]
+struct PriceBands {
+    static_percent: Option<Decimal>,
+    dynamic_percent: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RejectReason {
+    // Matching stopped before a fill outside the price bands and the book went into a
+    // volatility auction; the residual order is handed back.
+    PriceBandBreached(Decimal),
+}
+
+// Why a request was turned away without changing the book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OrderBookError {
+    // Not positive, or less than one lot.
+    InvalidQuantity(Decimal),
+    // A limit or trigger price that is not positive.
+    InvalidPrice(Decimal),
+    // The owner already has a live order with this client id.
+    DuplicateOrderId(i64),
+    UnknownOrderId(i64),
+    // Amends keep the side of the order they replace.
+    SideChangeNotAllowed,
+    // Post-only applies to limit and stop-limit orders only.
+    PostOnlyNotAllowed,
+    BookHalted,
+    BookClosed,
+    // The order type or time in force is not accepted in this session state.
+    NotAllowed(SessionState),
+    InvalidTransition {
+        from: SessionState,
+        to: SessionState,
+    },
+    UnknownSymbol,
+    DuplicateSymbol,
+}
+
+#[derive(Debug, Default)]
+struct TransitionResult {
+    // Set when the transition ended an auction.
+    auction: Option<AuctionResult>,
+    // DAY orders expired by closing the book, and the bracket exit legs that activated.
+    expired: Vec<Order>,
+    activated: Vec<AddOrderResult>,
+}
+
+// In an auction orders accumulate without trading until the auction closes
+// and the book uncrosses at a single price.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MatchingMode {
+    Continuous,
+    Auction,
+}
+
+// The price that maximises executed volume, and the buy minus sell quantity
+// left unmatched at that price.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Uncross {
+    price: Decimal,
+    volume: Decimal,
+    imbalance: Decimal,
+}
+
+// (order id, quantity) pairs in uncross priority.
+type AuctionQueue = Vec<(i64, Decimal)>;
+
+#[derive(Debug, Default)]
+struct AuctionResult {
+    uncross: Option<Uncross>,
+    // Auction fills report the buy order as taker and the sell order as maker.
+    fills: Vec<Fill>,
+    // Market orders that did not trade in full; they never rest.
+    cancelled_orders: Vec<Order>,
+    triggered: Vec<AddOrderResult>,
+}
+
+type GroupId = i64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GroupState {
+    // Bracket entry is working, the exit legs are not in the book yet.
+    Pending,
+    Active,
+    // A leg traded and the other legs were cancelled.
+    Completed,
+    Cancelled,
+}
+
+// Linked orders. The live legs are one-cancels-other: the first fill or cancel
+// on a leg cancels the others. A bracket keeps its exit legs back until the
+// entry is done and then sizes them to the quantity the entry filled.
+#[derive(Debug, Clone)]
+struct OrderGroup {
+    entry: Option<i64>,
+    pending_legs: Vec<Order>,
+    legs: Vec<i64>,
+    filled: HashMap<i64, Decimal>,
+    state: GroupState,
+}
+
+impl OrderGroup {
+    fn filled(&self, id: i64) -> Decimal {
+        self.filled.get(&id).copied().unwrap_or_default()
+    }
+}
+
+// Reference data for the instrument a book trades. Limits left as `None` do
+// not apply.
+#[derive(Debug, Clone)]
+struct Instrument {
+    symbol: String,
+    tick_size: Decimal,
+    lot_size: Decimal,
+    min_quantity: Option<Decimal>,
+    max_quantity: Option<Decimal>,
+    min_price: Option<Decimal>,
+    max_price: Option<Decimal>,
+    // Decimal places prices are shown with.
+    price_precision: u32,
+}
+
+impl Instrument {
+    fn new(symbol: &str) -> Self {
+        Instrument {
+            symbol: symbol.to_string(),
+            tick_size: dec!(0.01),
+            lot_size: dec!(1),
+            min_quantity: None,
+            max_quantity: None,
+            min_price: None,
+            max_price: None,
+            price_precision: 2,
+        }
+    }
+
+    fn with_tick_size(mut self, tick_size: Decimal, price_precision: u32) -> Self {
+        self.tick_size = tick_size;
+        self.price_precision = price_precision;
+        self
+    }
+
+    fn with_lot_size(mut self, lot_size: Decimal) -> Self {
+        self.lot_size = lot_size;
+        self
+    }
+
+    fn with_quantity_limits(mut self, min: Option<Decimal>, max: Option<Decimal>) -> Self {
+        self.min_quantity = min;
+        self.max_quantity = max;
+        self
+    }
+
+    fn with_price_limits(mut self, min: Option<Decimal>, max: Option<Decimal>) -> Self {
+        self.min_price = min;
+        self.max_price = max;
+        self
+    }
+
+    fn on_tick(&self, price: Decimal) -> bool {
+        (price % self.tick_size).is_zero()
+    }
+
+    // Snaps a price onto the tick away from the opposite side: down for buys, up for sells.
+    fn passive_tick(&self, side: &Side, price: Decimal) -> Decimal {
+        let ticks = price / self.tick_size;
+        let ticks = match side {
+            Side::Buy => ticks.floor(),
+            Side::Sell => ticks.ceil(),
+        };
+        ticks * self.tick_size
+    }
+
+    fn on_lot(&self, quantity: Decimal) -> bool {
+        (quantity % self.lot_size).is_zero()
+    }
+
+    fn accepts_quantity(&self, quantity: Decimal) -> bool {
+        quantity > Decimal::ZERO
+            && self.on_lot(quantity)
+            && self.min_quantity.is_none_or(|min| quantity >= min)
+            && self.max_quantity.is_none_or(|max| quantity <= max)
+    }
+
+    fn accepts_price(&self, price: Decimal) -> bool {
+        price > Decimal::ZERO
+            && self.on_tick(price)
+            && self.min_price.is_none_or(|min| price >= min)
+            && self.max_price.is_none_or(|max| price <= max)
+    }
+}
+
+// Where a resting order sits; the order itself is only stored in its level.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    side: Side,
+    price: Decimal,
+}
+
+#[derive(Debug)]
+struct OrderBook {
+    bids: BTreeMap<Decimal, PriceLevel>,
+    asks: BTreeMap<Decimal, PriceLevel>,
+    orders: HashMap<i64, Location>,
+    match_id: i64,
+    time: Timestamp,
+    instrument: Instrument,
+    stop_book: StopBook,
+    last_trade_price: Option<Decimal>,
+    // Ids of resting pegged orders in arrival order, which is the order they
+    // are re-slotted in when the reference prices move.
+    pegged: Vec<i64>,
+    peg_reference: (Option<Decimal>, Option<Decimal>),
+    groups: HashMap<GroupId, OrderGroup>,
+    order_groups: HashMap<i64, GroupId>,
+    next_group_id: GroupId,
+    next_order_id: i64,
+    allocation: Box<dyn AllocationStrategy>,
+    mode: MatchingMode,
+    session: SessionState,
+    price_bands: PriceBands,
+    // Anchor of the static collar, reset by every auction uncross.
+    reference_price: Option<Decimal>,
+    // Market orders collected during an auction.
+    auction_orders: Vec<Order>,
+    records: HashMap<i64, OrderRecord>,
+    // How long finished orders stay queryable once the clock moves on.
+    retention: Timestamp,
+    // Exchange ids of unfinished orders by owner and client id.
+    client_ids: HashMap<(Option<i64>, i64), i64>,
+}
+
+impl OrderBook {
+    fn new(instrument: Instrument) -> Self {
+        OrderBook {
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            orders: HashMap::new(),
+            match_id: 0,
+            time: 0,
+            instrument,
+            stop_book: StopBook::default(),
+            last_trade_price: None,
+            pegged: Vec::new(),
+            peg_reference: (None, None),
+            groups: HashMap::new(),
+            order_groups: HashMap::new(),
+            next_group_id: 1,
+            next_order_id: 1,
+            allocation: Box::new(Fifo),
+            mode: MatchingMode::Continuous,
+            session: SessionState::Continuous,
+            price_bands: PriceBands::default(),
+            reference_price: None,
+            auction_orders: Vec::new(),
+            records: HashMap::new(),
+            retention: 0,
+            client_ids: HashMap::new(),
+        }
+    }
+
+    fn with_retention(mut self, retention: Timestamp) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    fn with_price_bands(mut self, price_bands: PriceBands) -> Self {
+        self.price_bands = price_bands;
+        self
+    }
+
+    fn set_reference_price(&mut self, price: Decimal) -> Result<(), OrderBookError> {
+        if price <= Decimal::ZERO {
+            return Err(OrderBookError::InvalidPrice(price));
+        }
+        self.reference_price = Some(price);
+        Ok(())
+    }
+
+    // The furthest price an order on `side` may trade at under the price bands.
+    fn band_limit(&self, side: &Side) -> Option<Decimal> {
+        let limits = [
+            (self.reference_price, self.price_bands.static_percent),
+            (self.last_trade_price, self.price_bands.dynamic_percent),
+        ]
+        .into_iter()
+        .filter_map(|(price, percent)| {
+            let (price, percent) = (price?, percent?);
+            let distance = price * percent / dec!(100);
+            Some(match side {
+                Side::Buy => price + distance,
+                Side::Sell => price - distance,
+            })
+        });
+        match side {
+            Side::Buy => limits.min(),
+            Side::Sell => limits.max(),
+        }
+    }
+
+    fn with_allocation(mut self, allocation: impl AllocationStrategy + 'static) -> Self {
+        self.allocation = Box::new(allocation);
+        self
+    }
+
+    fn print_book(&self) {
+        println!("## Orderbook {}", self.instrument.symbol);
+        let precision = self.instrument.price_precision as usize;
+        println!("{:<8} {:<8} {:<8} {:<8}", "ID", "Side", "Volume", "Price");
+
+        for (price, level) in self.asks.iter().rev() {
+            for order in level.displayed.iter() {
+                println!(
+                    "{:<8} {:<8} {:<8} {:<8.*}",
+                    order.id, order.side, order.quantity, precision, price
+                );
+            }
+        }
+
+        println!("{:-<32}", "");
+
+        for (price, level) in self.bids.iter().rev() {
+            for order in level.displayed.iter() {
+                println!(
+                    "{:<8} {:<8} {:<8} {:<8.*}",
+                    order.id, order.side, order.quantity, precision, price
+                );
+            }
+        }
+    }
+
+    // Aggregated displayed quantity per price, best price first.
+    fn depth(&self, side: &Side, levels: usize) -> Vec<(Decimal, Decimal)> {
+        let book_side: Box<dyn Iterator<Item = (&Decimal, &PriceLevel)>> = match side {
+            Side::Buy => Box::new(self.bids.iter().rev()),
+            Side::Sell => Box::new(self.asks.iter()),
+        };
+        book_side
+            .map(|(&price, level)| (price, level.displayed_quantity()))
+            .filter(|&(_, quantity)| quantity > Decimal::ZERO)
+            .take(levels)
+            .collect()
+    }
+
+    fn best_opposite_price(&self, side: &Side) -> Option<Decimal> {
+        match side {
+            Side::Buy => self.asks.keys().next().copied(),
+            Side::Sell => self.bids.keys().next_back().copied(),
+        }
+    }
+
+    // The opposite-side level that ranks right after `price`.
+    fn next_opposite_price(&self, side: &Side, price: Decimal) -> Option<Decimal> {
+        match side {
+            Side::Buy => self.asks.range((Excluded(price), Unbounded)).next(),
+            Side::Sell => self.bids.range(..price).next_back(),
+        }
+        .map(|(&price, _)| price)
+    }
+
+    // Crossing levels on the opposite side, best price first.
+    fn opposite_levels<'a>(
+        &'a self,
+        side: &Side,
+    ) -> Box<dyn Iterator<Item = (&'a Decimal, &'a PriceLevel)> + 'a> {
+        match side {
+            Side::Buy => Box::new(self.asks.iter()),
+            Side::Sell => Box::new(self.bids.iter().rev()),
+        }
+    }
+
+    // Dry run of `match_order`: how much of `order` could trade right now. Each
+    // queue is shared out by the book's strategy and icebergs that trade their
+    // whole slice add their reserve. Own orders never trade; self-trade
+    // prevention skips them, decrements the order or stops it there.
+    fn available_liquidity(&self, order: &Order) -> Decimal {
+        let mut remaining = order.quantity;
+        let mut available = Decimal::ZERO;
+        let band_limit = self.band_limit(&order.side);
+        for (_, level) in self.opposite_levels(&order.side).take_while(|(price, _)| {
+            order.crosses(**price) && within_band(&order.side, **price, band_limit)
+        }) {
+            for queue in [&level.displayed, &level.hidden] {
+                if remaining.is_zero() {
+                    return available;
+                }
+                let resting = queue.eligible(remaining, self.allocation.time_priority());
+                let allocations = allocate_queue(
+                    &*self.allocation,
+                    remaining,
+                    self.instrument.lot_size,
+                    &resting,
+                );
+                let mut traded = vec![Decimal::ZERO; resting.len()];
+                for (i, quantity) in allocations {
+                    let maker_order = resting[i];
+                    if maker_order.self_trades_with(order) {
+                        match order.self_trade_prevention {
+                            SelfTradePrevention::CancelOldest => continue,
+                            SelfTradePrevention::Decrement => {
+                                remaining -= remaining.min(maker_order.total_quantity());
+                                continue;
+                            }
+                            SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => {
+                                return available;
+                            }
+                        }
+                    }
+                    let quantity = quantity.min(remaining);
+                    traded[i] += quantity;
+                    remaining -= quantity;
+                    available += quantity;
+                }
+                let reserve: Decimal = resting
+                    .iter()
+                    .zip(&traded)
+                    .filter(|(maker_order, &traded)| traded == maker_order.quantity)
+                    .map(|(maker_order, _)| maker_order.reserve)
+                    .sum();
+                let refills = reserve.min(remaining);
+                remaining -= refills;
+                available += refills;
+            }
+        }
+        available
+    }
+
+    fn add_order(&mut self, mut order: Order) -> Result<AddOrderResult, OrderBookError> {
+        self.validate(&order)?;
+        self.check_unique(&order)?;
+        self.check_session(&order)?;
+        self.assign_id(&mut order);
+        Ok(self.enter_order(order))
+    }
+
+    fn assign_id(&mut self, order: &mut Order) {
+        order.id = self.next_order_id;
+        self.next_order_id += 1;
+        self.client_ids
+            .insert((order.owner, order.client_id), order.id);
+        self.records.insert(
+            order.id,
+            OrderRecord {
+                client_id: order.client_id,
+                owner: order.owner,
+                status: OrderStatus::New,
+                original_quantity: order.total_quantity(),
+                remaining_quantity: Decimal::ZERO,
+                filled_quantity: Decimal::ZERO,
+                notional: Decimal::ZERO,
+                created: self.time,
+                updated: self.time,
+            },
+        );
+    }
+
+    fn get_order(&self, id: i64) -> Option<OrderRecord> {
+        let mut record = self.records.get(&id)?.clone();
+        record.remaining_quantity = self.remaining_quantity(id).unwrap_or_default();
+        Some(record)
+    }
+
+    // Books the fills of one order's placement and updates everyone involved.
+    fn track(&mut self, result: &AddOrderResult) {
+        self.record_fills(&result.fills);
+        let rejected = result.reject_reason.is_some()
+            || result.post_only_action == Some(PostOnlyAction::Rejected);
+        let cancelled = result.cancelled_quantity > Decimal::ZERO;
+        self.refresh_record(result.order_id, rejected, cancelled);
+        for fill in &result.fills {
+            let cancelled = result.self_trade_cancelled.contains(&fill.maker_id);
+            self.refresh_record(fill.maker_id, false, cancelled);
+        }
+        for &id in &result.self_trade_cancelled {
+            self.refresh_record(id, false, true);
+        }
+    }
+
+    fn record_fills(&mut self, fills: &[Fill]) {
+        for fill in fills {
+            for id in [fill.taker_id, fill.maker_id] {
+                if let Some(record) = self.records.get_mut(&id) {
+                    record.filled_quantity += fill.volume;
+                    record.notional += fill.volume * fill.price;
+                }
+            }
+        }
+    }
+
+    // Derives the status of an order from whether it is still working and what
+    // it has filled. `cancelled` marks an order that went away short of a full fill;
+    // a rejected order that traded first counts as cancelled.
+    fn refresh_record(&mut self, id: i64, rejected: bool, cancelled: bool) {
+        let live = self.is_live(id);
+        let Some(record) = self.records.get_mut(&id) else {
+            return;
+        };
+        let filled = record.filled_quantity > Decimal::ZERO;
+        record.status = match (live, filled) {
+            (true, true) => OrderStatus::PartiallyFilled,
+            (true, false) => record.status,
+            (false, false) if rejected => OrderStatus::Rejected,
+            (false, true) if !cancelled && !rejected => OrderStatus::Filled,
+            (false, _) => OrderStatus::Cancelled,
+        };
+        record.updated = self.time;
+        self.release_client_id(id);
+    }
+
+    fn set_status(&mut self, id: i64, status: OrderStatus) {
+        if let Some(record) = self.records.get_mut(&id) {
+            record.status = status;
+            record.updated = self.time;
+        }
+        self.release_client_id(id);
+    }
+
+    // Frees the client id of a finished order for reuse.
+    fn release_client_id(&mut self, id: i64) {
+        let Some(record) = self.records.get(&id) else {
+            return;
+        };
+        let key = (record.owner, record.client_id);
+        if record.status.is_terminal() && self.client_ids.get(&key) == Some(&id) {
+            self.client_ids.remove(&key);
+        }
+    }
+
+    fn validate(&self, order: &Order) -> Result<(), OrderBookError> {
+        let instrument = &self.instrument;
+        let quantity = order.total_quantity();
+        if !instrument.accepts_quantity(quantity) {
+            return Err(OrderBookError::InvalidQuantity(quantity));
+        }
+        for quantity in [order.display_quantity, order.min_quantity]
+            .into_iter()
+            .flatten()
+        {
+            if quantity <= Decimal::ZERO || !instrument.on_lot(quantity) {
+                return Err(OrderBookError::InvalidQuantity(quantity));
+            }
+        }
+
+        let limit = matches!(order.order_type, OrderType::Limit | OrderType::StopLimit(_));
+        if order.post_only.is_some() && !limit {
+            return Err(OrderBookError::PostOnlyNotAllowed);
+        }
+        let mut prices = Vec::new();
+        if limit && order.peg.is_none() {
+            prices.push(order.price);
+        }
+        if order.trail.is_none() {
+            prices.extend(order.trigger_price());
+        }
+        if let Some(price) = prices.into_iter().find(|&p| !instrument.accepts_price(p)) {
+            return Err(OrderBookError::InvalidPrice(price));
+        }
+
+        // Offsets are price distances: on tick, and a trail must be positive.
+        let offset = match (order.trail, &order.peg) {
+            (Some(Trail::Percent(percent)), _) if percent <= Decimal::ZERO => Some(percent),
+            (Some(Trail::Amount(amount)), _)
+                if amount <= Decimal::ZERO || !instrument.on_tick(amount) =>
+            {
+                Some(amount)
+            }
+            (_, Some(peg)) if !instrument.on_tick(peg.offset) => Some(peg.offset),
+            _ => None,
+        };
+        match offset {
+            Some(offset) => Err(OrderBookError::InvalidPrice(offset)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_unique(&self, order: &Order) -> Result<(), OrderBookError> {
+        if self
+            .client_ids
+            .contains_key(&(order.owner, order.client_id))
+        {
+            return Err(OrderBookError::DuplicateOrderId(order.client_id));
+        }
+        Ok(())
+    }
+
+    fn check_session(&self, order: &Order) -> Result<(), OrderBookError> {
+        if self.session.allows(order) {
+            return Ok(());
+        }
+        Err(match self.session {
+            SessionState::Halted => OrderBookError::BookHalted,
+            SessionState::Closed => OrderBookError::BookClosed,
+            session => OrderBookError::NotAllowed(session),
+        })
+    }
+
+    // Enters an order that has passed validation.
+    fn enter_order(&mut self, order: Order) -> AddOrderResult {
+        let mut result = self.place_order(order);
+        self.track(&result);
+        result.triggered = self.trigger_stops();
+        let activated = self.update_groups(&result);
+        result.triggered.extend(activated);
+        self.reprice_pegged();
+        result
+    }
+
+    // Stops stay parked while the book is halted or closed and fire on the
+    // next order once it trades again.
+    fn trigger_stops(&mut self) -> Vec<AddOrderResult> {
+        let mut triggered = Vec::new();
+        if !matches!(
+            self.session,
+            SessionState::Continuous | SessionState::Auction
+        ) {
+            return triggered;
+        }
+        while let Some(stop) = self
+            .last_trade_price
+            .and_then(|last_price| self.stop_book.pop_triggered(last_price))
+        {
+            let result = self.place_order(stop.activate());
+            self.track(&result);
+            triggered.push(result);
+        }
+        triggered
+    }
+
+    fn place_order(&mut self, mut order: Order) -> AddOrderResult {
+        let mut result = AddOrderResult {
+            order_id: order.id,
+            client_id: order.client_id,
+            ..Default::default()
+        };
+        // An incoming iceberg trades its whole size; the peak only applies once it rests.
+        order.quantity = order.total_quantity();
+        order.reserve = Decimal::ZERO;
+
+        if order.trigger_price().is_some() {
+            if let Some(trail) = order.trail {
+                let Some(last_price) = self.last_trade_price else {
+                    result.cancelled_quantity = order.quantity;
+                    return result;
+                };
+                order.set_trigger_price(trail.trigger_price(&order.side, last_price));
+            }
+            self.stop_book.insert(order);
+            return result;
+        }
+
+        let unfillable = order.time_in_force == TimeInForce::FillOrKill
+            && self.available_liquidity(&order) < order.quantity;
+        if unfillable || order.is_expired(self.time) {
+            result.cancelled_quantity = order.quantity;
+            return result;
+        }
+
+        if order.peg.is_some() {
+            match self.peg_price(&order, self.reference_prices()) {
+                Some(price) => order.price = price,
+                None => {
+                    result.cancelled_quantity = order.quantity;
+                    return result;
+                }
+            }
+        }
+
+        if self.mode == MatchingMode::Auction && order.order_type == OrderType::Market {
+            self.auction_orders.push(order);
+            return result;
+        }
+
+        if let Some(post_only) = order.post_only {
+            result.post_only_action = Some(PostOnlyAction::Posted);
+            let best_price = self.best_opposite_price(&order.side);
+            if let Some(best_price) = best_price.filter(|&price| order.crosses(price)) {
+                if post_only == PostOnly::Reject {
+                    result.post_only_action = Some(PostOnlyAction::Rejected);
+                    result.cancelled_quantity = order.quantity;
+                    return result;
+                }
+                let price = match order.side {
+                    Side::Buy => best_price - self.instrument.tick_size,
+                    Side::Sell => best_price + self.instrument.tick_size,
+                };
+                if !self.instrument.accepts_price(price) {
+                    result.post_only_action = Some(PostOnlyAction::Rejected);
+                    result.cancelled_quantity = order.quantity;
+                    return result;
+                }
+                order.price = price;
+                result.post_only_action = Some(PostOnlyAction::Repriced(order.price));
+            }
+        }
+
+        let required_quantity = if order.all_or_none {
+            Some(order.quantity)
+        } else {
+            order.min_quantity
+        };
+        // Triggered stops and activated legs can arrive outside continuous
+        // trading; they rest or are cancelled without matching.
+        let continuous =
+            self.mode == MatchingMode::Continuous && self.session == SessionState::Continuous;
+        if continuous
+            && required_quantity.is_none_or(|required| self.available_liquidity(&order) >= required)
+        {
+            let band_limit = self.band_limit(&order.side);
+            self.match_order(&mut order, band_limit, &mut result);
+
+            let beyond_band = band_limit
+                .and_then(|limit| self.next_opposite_price(&order.side, limit))
+                .filter(|&price| order.crosses(price));
+            if let Some(price) = beyond_band.filter(|_| order.quantity > Decimal::ZERO) {
+                self.session = SessionState::Auction;
+                self.start_auction();
+                result.reject_reason = Some(RejectReason::PriceBandBreached(price));
+                result.residual = Some(order);
+                return result;
+            }
+        }
+
+        if order.quantity > Decimal::ZERO {
+            if order.rests() {
+                self.rest_order(order);
+            } else {
+                result.cancelled_quantity = order.quantity;
+            }
+        }
+        result
+    }
+
+    fn rest_order(&mut self, mut order: Order) {
+        if order.peg.is_some() && !self.pegged.contains(&order.id) {
+            self.pegged.push(order.id);
+        }
+        order.show_peak();
+        let book_side = match order.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        self.orders.insert(
+            order.id,
+            Location {
+                side: order.side,
+                price: order.price,
+            },
+        );
+        book_side.entry(order.price).or_default().push(order);
+    }
+
+    // Best displayed bid and ask among orders that are not pegged themselves.
+    fn reference_prices(&self) -> (Option<Decimal>, Option<Decimal>) {
+        let unpegged = |level: &PriceLevel| level.displayed.iter().any(|o| o.peg.is_none());
+        let best_bid = self.bids.iter().rev().find(|(_, level)| unpegged(level));
+        let best_ask = self.asks.iter().find(|(_, level)| unpegged(level));
+        (
+            best_bid.map(|(&price, _)| price),
+            best_ask.map(|(&price, _)| price),
+        )
+    }
+
+    fn peg_price(
+        &self,
+        order: &Order,
+        (best_bid, best_ask): (Option<Decimal>, Option<Decimal>),
+    ) -> Option<Decimal> {
+        let peg = order.peg?;
+        let (same_best, opposite_best) = match order.side {
+            Side::Buy => (best_bid, best_ask),
+            Side::Sell => (best_ask, best_bid),
+        };
+        let reference = match peg.reference {
+            PegReference::Primary => same_best?,
+            PegReference::Midpoint => (best_bid? + best_ask?) / dec!(2),
+            PegReference::Market => opposite_best?,
+        };
+        let price = self
+            .instrument
+            .passive_tick(&order.side, reference + peg.offset);
+
+        // Pegged orders never take liquidity, they stay a tick behind the opposite side.
+        Some(match (&order.side, self.best_opposite_price(&order.side)) {
+            (Side::Buy, Some(best_price)) => price.min(best_price - self.instrument.tick_size),
+            (Side::Sell, Some(best_price)) => price.max(best_price + self.instrument.tick_size),
+            (_, None) => price,
+        })
+    }
+
+    // Re-slots pegged orders whose price moved with the reference prices. They
+    // are visited in arrival order and each one goes to the back of its new
+    // level; orders that lost their reference keep their last price.
+    fn reprice_pegged(&mut self) {
+        let reference = self.reference_prices();
+        if reference == self.peg_reference {
+            return;
+        }
+        self.peg_reference = reference;
+        self.pegged.retain(|id| self.orders.contains_key(id));
+
+        for id in self.pegged.clone() {
+            let order = self.find_order(id).unwrap();
+            match self.peg_price(order, reference) {
+                Some(price) if price != order.price => {
+                    let mut order = self.take_order(id).unwrap();
+                    order.price = price;
+                    self.rest_order(order);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Walks the opposite side from the best price outwards until the order is
+    // filled or no longer crosses.
+    fn match_order(
+        &mut self,
+        order: &mut Order,
+        band_limit: Option<Decimal>,
+        result: &mut AddOrderResult,
+    ) {
+        let mut next_price = self.best_opposite_price(&order.side);
+
+        while order.quantity > Decimal::ZERO {
+            let Some(level_price) = next_price else {
+                break;
+            };
+            if !order.crosses(level_price) || !within_band(&order.side, level_price, band_limit) {
+                break;
+            }
+            // Levels can survive a pass when their all-or-none orders were skipped.
+            next_price = self.next_opposite_price(&order.side, level_price);
+
+            let book_side = match order.side {
+                Side::Buy => &mut self.asks,
+                Side::Sell => &mut self.bids,
+            };
+            let level = book_side.get_mut(&level_price).unwrap();
+
+            for queue in [&mut level.displayed, &mut level.hidden] {
+                // The strategy shares out the queue once. Later passes only trade iceberg
+                // slices refilled during this match, in time priority at the back of the
+                // queue, unless self-trade prevention cut the last pass short and the
+                // queue is shared out afresh.
+                let mut refilled: Vec<i64> = Vec::new();
+                let mut fresh = true;
+                while order.quantity > Decimal::ZERO {
+                    let resting: Vec<&Order> = if fresh || self.allocation.time_priority() {
+                        queue.eligible(order.quantity, self.allocation.time_priority())
+                    } else {
+                        refilled.iter().filter_map(|&id| queue.get(id)).collect()
+                    };
+                    let strategy: &dyn AllocationStrategy =
+                        if fresh { &*self.allocation } else { &Fifo };
+                    let allocations = allocate_queue(
+                        strategy,
+                        order.quantity,
+                        self.instrument.lot_size,
+                        &resting,
+                    );
+                    if allocations.is_empty() {
+                        break;
+                    }
+
+                    // Allocations are traded up to the first one that would be a self-trade.
+                    let self_trade = allocations
+                        .iter()
+                        .position(|&(i, _)| order.self_trades_with(resting[i]));
+                    let end = self_trade.map_or(allocations.len(), |pos| pos + 1);
+                    let mut touched: Vec<usize> =
+                        allocations[..end].iter().map(|&(i, _)| i).collect();
+                    touched.sort();
+                    touched.dedup();
+                    let touched: Vec<i64> = touched.into_iter().map(|i| resting[i].id).collect();
+                    let allocations: Vec<(i64, Decimal)> = allocations
+                        .into_iter()
+                        .map(|(i, quantity)| (resting[i].id, quantity))
+                        .collect();
+                    let trades = &allocations[..self_trade.unwrap_or(allocations.len())];
+                    fresh = self_trade.is_some();
+
+                    for &(id, trade_quantity) in trades {
+                        let maker_order = queue.get_mut(id).unwrap();
+                        self.match_id += 1;
+                        self.last_trade_price = Some(level_price);
+                        self.stop_book.trail(level_price);
+                        result.fills.push(Fill {
+                            matched_id: self.match_id,
+                            volume: trade_quantity,
+                            price: level_price,
+                            taker_id: order.id,
+                            maker_id: maker_order.id,
+                        });
+
+                        maker_order.quantity -= trade_quantity;
+                        order.quantity -= trade_quantity;
+                    }
+
+                    if let Some(pos) = self_trade {
+                        let maker_order = queue.get_mut(allocations[pos].0).unwrap();
+                        let prevented = order.quantity.min(maker_order.total_quantity());
+                        result.self_trade_prevented += prevented;
+
+                        let (cancel_maker, cancel_taker) = match order.self_trade_prevention {
+                            SelfTradePrevention::CancelNewest => (false, true),
+                            SelfTradePrevention::CancelOldest => (true, false),
+                            SelfTradePrevention::CancelBoth => (true, true),
+                            SelfTradePrevention::Decrement => {
+                                order.quantity -= prevented;
+                                maker_order.quantity = maker_order.total_quantity() - prevented;
+                                maker_order.reserve = Decimal::ZERO;
+                                maker_order.show_peak();
+                                if maker_order.quantity.is_zero() {
+                                    result.self_trade_cancelled.push(maker_order.id);
+                                }
+                                (false, false)
+                            }
+                        };
+                        if cancel_maker {
+                            result.self_trade_cancelled.push(maker_order.id);
+                            maker_order.quantity = Decimal::ZERO;
+                            maker_order.reserve = Decimal::ZERO;
+                        }
+                        if cancel_taker {
+                            result.self_trade_cancelled.push(order.id);
+                            result.cancelled_quantity += order.quantity;
+                            order.quantity = Decimal::ZERO;
+                        }
+                    }
+
+                    // Refilled iceberg slices lose time priority and join the back of the queue.
+                    for id in touched {
+                        if queue.get(id).unwrap().quantity > Decimal::ZERO {
+                            continue;
+                        }
+                        let mut maker_order = queue.remove(id).unwrap();
+                        if maker_order.reserve > Decimal::ZERO {
+                            maker_order.show_peak();
+                            queue.push_back(maker_order);
+                            refilled.retain(|&slice| slice != id);
+                            refilled.push(id);
+                        } else {
+                            self.orders.remove(&id);
+                        }
+                    }
+                }
+            }
+
+            if level.is_empty() {
+                book_side.remove(&level_price);
+            }
+        }
+    }
+
+    // Cancels are accepted in every session state but `Closed`.
+    fn remove_order(&mut self, id: i64) -> Result<CancelResult, OrderBookError> {
+        if self.session == SessionState::Closed {
+            return Err(OrderBookError::BookClosed);
+        }
+        let order = self
+            .take_order(id)
+            .ok_or(OrderBookError::UnknownOrderId(id))?;
+        self.set_status(id, OrderStatus::Cancelled);
+        let activated = self.settle_order_group(id);
+        self.reprice_pegged();
+        Ok(CancelResult { order, activated })
+    }
+
+    fn is_live(&self, id: i64) -> bool {
+        self.find_order(id).is_some()
+    }
+
+    // A working order as it stands now, with its remaining quantity.
+    fn find_order(&self, id: i64) -> Option<&Order> {
+        if let Some(location) = self.orders.get(&id) {
+            let book_side = match location.side {
+                Side::Buy => &self.bids,
+                Side::Sell => &self.asks,
+            };
+            return book_side
+                .get(&location.price)
+                .and_then(|level| level.get(id));
+        }
+        self.stop_book
+            .get(id)
+            .or_else(|| self.auction_orders.iter().find(|o| o.id == id))
+    }
+
+    // Displayed plus reserve quantity still working, or `None` once the order is gone.
+    fn remaining_quantity(&self, id: i64) -> Option<Decimal> {
+        self.find_order(id).map(Order::total_quantity)
+    }
+
+    // Every working order: resting, waiting for its trigger or held for the auction.
+    fn live_orders(&self) -> impl Iterator<Item = &Order> {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .flat_map(PriceLevel::orders)
+            .chain(self.stop_book.orders())
+            .chain(&self.auction_orders)
+    }
+
+    fn group(&self, group_id: GroupId) -> Option<&OrderGroup> {
+        self.groups.get(&group_id)
+    }
+
+    fn new_group(&mut self, entry: Option<i64>, pending_legs: Vec<Order>) -> GroupId {
+        let group_id = self.next_group_id;
+        self.next_group_id += 1;
+        let state = match entry {
+            Some(_) => GroupState::Pending,
+            None => GroupState::Active,
+        };
+        self.groups.insert(
+            group_id,
+            OrderGroup {
+                entry,
+                pending_legs,
+                legs: Vec::new(),
+                filled: HashMap::new(),
+                state,
+            },
+        );
+        group_id
+    }
+
+    fn add_oco(
+        &mut self,
+        mut first: Order,
+        mut second: Order,
+    ) -> Result<(GroupId, Vec<AddOrderResult>), OrderBookError> {
+        self.check_group(&[&first, &second])?;
+        self.check_session(&first)?;
+        self.check_session(&second)?;
+        self.assign_id(&mut first);
+        self.assign_id(&mut second);
+        let group_id = self.new_group(None, Vec::new());
+        let results = self.place_legs(group_id, vec![first, second]);
+        Ok((group_id, results))
+    }
+
+    fn add_bracket(
+        &mut self,
+        mut entry: Order,
+        mut take_profit: Order,
+        mut stop_loss: Order,
+    ) -> Result<(GroupId, AddOrderResult), OrderBookError> {
+        self.check_group(&[&entry, &take_profit, &stop_loss])?;
+        self.check_session(&entry)?;
+        for order in [&mut entry, &mut take_profit, &mut stop_loss] {
+            self.assign_id(order);
+        }
+        let group_id = self.new_group(Some(entry.id), vec![take_profit, stop_loss]);
+        self.order_groups.insert(entry.id, group_id);
+        Ok((group_id, self.enter_order(entry)))
+    }
+
+    // Every order of a group is validated before any of it is placed.
+    fn check_group(&self, orders: &[&Order]) -> Result<(), OrderBookError> {
+        for (i, order) in orders.iter().enumerate() {
+            self.validate(order)?;
+            self.check_unique(order)?;
+            if orders[..i]
+                .iter()
+                .any(|o| o.client_id == order.client_id && o.owner == order.owner)
+            {
+                return Err(OrderBookError::DuplicateOrderId(order.client_id));
+            }
+        }
+        Ok(())
+    }
+
+    // Legs are placed one at a time; once an earlier leg has traded or gone
+    // away the rest are cancelled without being placed.
+    fn place_legs(&mut self, group_id: GroupId, legs: Vec<Order>) -> Vec<AddOrderResult> {
+        let mut results = Vec::new();
+        for leg in legs {
+            let group = self.groups.get_mut(&group_id).unwrap();
+            if group.state != GroupState::Active {
+                self.set_status(leg.id, OrderStatus::Cancelled);
+                continue;
+            }
+            group.legs.push(leg.id);
+            self.order_groups.insert(leg.id, group_id);
+            results.push(self.enter_order(leg));
+        }
+        results
+    }
+
+    // Books the fills of `result` against any groups involved and settles them.
+    fn update_groups(&mut self, result: &AddOrderResult) -> Vec<AddOrderResult> {
+        let mut touched = Vec::new();
+        for result in result.flatten() {
+            touched.extend(self.order_groups.get(&result.order_id));
+            for id in &result.self_trade_cancelled {
+                touched.extend(self.order_groups.get(id));
+            }
+            for fill in &result.fills {
+                for id in [fill.taker_id, fill.maker_id] {
+                    if let Some(&group_id) = self.order_groups.get(&id) {
+                        let group = self.groups.get_mut(&group_id).unwrap();
+                        *group.filled.entry(id).or_default() += fill.volume;
+                        touched.push(group_id);
+                    }
+                }
+            }
+        }
+        touched.sort();
+        touched.dedup();
+        touched
+            .into_iter()
+            .flat_map(|group_id| self.settle_group(group_id))
+            .collect()
+    }
+
+    fn settle_order_group(&mut self, id: i64) -> Vec<AddOrderResult> {
+        match self.order_groups.get(&id) {
+            Some(&group_id) => self.settle_group(group_id),
+            None => Vec::new(),
+        }
+    }
+
+    fn settle_group(&mut self, group_id: GroupId) -> Vec<AddOrderResult> {
+        let group = &self.groups[&group_id];
+        match group.state {
+            GroupState::Pending => {
+                let entry = group.entry.unwrap();
+                if self.is_live(entry) {
+                    return Vec::new();
+                }
+                let filled = group.filled(entry);
+                let group = self.groups.get_mut(&group_id).unwrap();
+                if filled.is_zero() {
+                    group.state = GroupState::Cancelled;
+                    for leg in std::mem::take(&mut group.pending_legs) {
+                        self.set_status(leg.id, OrderStatus::Cancelled);
+                    }
+                    return Vec::new();
+                }
+                group.state = GroupState::Active;
+                let mut legs = std::mem::take(&mut group.pending_legs);
+                for leg in &mut legs {
+                    leg.quantity = filled;
+                }
+                self.place_legs(group_id, legs)
+            }
+            GroupState::Active => {
+                let traded = group
+                    .legs
+                    .iter()
+                    .find(|&&id| group.filled(id) > Decimal::ZERO);
+                let Some(&done) =
+                    traded.or_else(|| group.legs.iter().find(|&&id| !self.is_live(id)))
+                else {
+                    return Vec::new();
+                };
+                let state = match traded {
+                    Some(_) => GroupState::Completed,
+                    None => GroupState::Cancelled,
+                };
+                let others: Vec<i64> = group
+                    .legs
+                    .iter()
+                    .copied()
+                    .filter(|&id| id != done)
+                    .collect();
+                self.groups.get_mut(&group_id).unwrap().state = state;
+                for id in others {
+                    if self.take_order(id).is_some() {
+                        self.set_status(id, OrderStatus::Cancelled);
+                    }
+                }
+                Vec::new()
+            }
+            GroupState::Completed | GroupState::Cancelled => Vec::new(),
+        }
+    }
+
+    fn take_order(&mut self, id: i64) -> Option<Order> {
+        if let Some(location) = self.orders.remove(&id) {
+            let book_side = match location.side {
+                Side::Buy => &mut self.bids,
+                Side::Sell => &mut self.asks,
+            };
+
+            if let Some(level) = book_side.get_mut(&location.price) {
+                if let Some(removed) = level.remove(id) {
+                    if level.is_empty() {
+                        book_side.remove(&location.price);
+                    }
+                    return Some(removed);
+                }
+            }
+        }
+        if let Some(order) = self.stop_book.remove(id) {
+            return Some(order);
+        }
+        let pos = self.auction_orders.iter().position(|o| o.id == id)?;
+        Some(self.auction_orders.remove(pos))
+    }
+
+    // Moves the session along. Entering pre-open or an auction starts
+    // collecting orders, leaving it for continuous trading or the close
+    // uncrosses the book, and closing expires DAY orders.
+    fn transition(&mut self, to: SessionState) -> Result<TransitionResult, OrderBookError> {
+        let from = self.session;
+        if !from.can_transition_to(to) {
+            return Err(OrderBookError::InvalidTransition { from, to });
+        }
+        self.session = to;
+
+        let mut result = TransitionResult::default();
+        match to {
+            SessionState::PreOpen | SessionState::Auction => self.start_auction(),
+            SessionState::Continuous | SessionState::Closed
+                if self.mode == MatchingMode::Auction =>
+            {
+                result.auction = Some(self.close_auction());
+            }
+            _ => {}
+        }
+        if to == SessionState::Closed {
+            let expiry = self.end_session();
+            result.expired = expiry.expired;
+            result.activated = expiry.activated;
+        }
+        Ok(result)
+    }
+
+    fn start_auction(&mut self) {
+        self.mode = MatchingMode::Auction;
+    }
+
+    // The price the book would uncross at if the auction closed now: most
+    // executed volume, then smallest imbalance, then closest to the last trade
+    // price, then the lowest price.
+    fn indicative_uncross(&self) -> Option<Uncross> {
+        let mut prices: Vec<Decimal> = self.bids.keys().chain(self.asks.keys()).copied().collect();
+        prices.sort();
+        prices.dedup();
+        if prices.is_empty() {
+            prices.extend(self.last_trade_price);
+        }
+        prices
+            .into_iter()
+            .map(|price| {
+                let (buys, sells) = self.uncross_orders(price);
+                let demand: Decimal = buys.iter().map(|&(_, quantity)| quantity).sum();
+                let supply: Decimal = sells.iter().map(|&(_, quantity)| quantity).sum();
+                Uncross {
+                    price,
+                    volume: demand.min(supply),
+                    imbalance: demand - supply,
+                }
+            })
+            .filter(|uncross| uncross.volume > Decimal::ZERO)
+            .min_by_key(|uncross| {
+                (
+                    Reverse(uncross.volume),
+                    uncross.imbalance.abs(),
+                    self.last_trade_price
+                        .map(|last_price| (uncross.price - last_price).abs()),
+                    uncross.price,
+                )
+            })
+    }
+
+    // The orders that take part in an uncross at `price`, each side in priority
+    // order: market orders, then limit orders by price and time. An all-or-none
+    // order only takes part if it fills in full next to the other orders that
+    // do; buys are considered before sells.
+    fn uncross_orders(&self, price: Decimal) -> (AuctionQueue, AuctionQueue) {
+        let side_orders = |side: Side| -> Vec<&Order> {
+            let levels: Box<dyn Iterator<Item = &PriceLevel>> = match side {
+                Side::Buy => Box::new(self.bids.range(price..).rev().map(|(_, level)| level)),
+                Side::Sell => Box::new(self.asks.range(..=price).map(|(_, level)| level)),
+            };
+            self.auction_orders
+                .iter()
+                .filter(|o| o.side == side)
+                .chain(levels.flat_map(PriceLevel::orders))
+                .collect()
+        };
+        let firm = |orders: &[&Order]| -> Decimal {
+            orders
+                .iter()
+                .filter(|o| !o.all_or_none)
+                .map(|o| o.total_quantity())
+                .sum()
+        };
+        let include = |orders: &[&Order], own: &mut Decimal, other: Decimal| {
+            orders
+                .iter()
+                .filter(|o| {
+                    if !o.all_or_none {
+                        return true;
+                    }
+                    let fits = *own + o.total_quantity() <= other;
+                    if fits {
+                        *own += o.total_quantity();
+                    }
+                    fits
+                })
+                .map(|o| (o.id, o.total_quantity()))
+                .collect::<Vec<_>>()
+        };
+        let (buys, sells) = (side_orders(Side::Buy), side_orders(Side::Sell));
+        let (mut demand, mut supply) = (firm(&buys), firm(&sells));
+        let buys = include(&buys, &mut demand, supply);
+        let sells = include(&sells, &mut supply, demand);
+        (buys, sells)
+    }
+
+    // Uncrosses the book at the indicative price and returns to continuous
+    // trading. Market orders go first, then limit orders in price-time priority.
+    // Self-trade prevention does not apply: an owner's buy and sell can trade
+    // with each other at the uncross.
+    fn close_auction(&mut self) -> AuctionResult {
+        let mut result = AuctionResult {
+            uncross: self.indicative_uncross(),
+            ..Default::default()
+        };
+        let priority = result
+            .uncross
+            .map(|uncross| self.uncross_orders(uncross.price));
+        self.mode = MatchingMode::Continuous;
+        let mut market_orders = std::mem::take(&mut self.auction_orders);
+
+        if let (Some(uncross), Some((mut buys, mut sells))) = (result.uncross, priority) {
+            let mut executed: HashMap<i64, Decimal> = HashMap::new();
+            let (mut b, mut s, mut remaining) = (0, 0, uncross.volume);
+            while remaining > Decimal::ZERO {
+                let volume = buys[b].1.min(sells[s].1).min(remaining);
+                self.match_id += 1;
+                result.fills.push(Fill {
+                    matched_id: self.match_id,
+                    volume,
+                    price: uncross.price,
+                    taker_id: buys[b].0,
+                    maker_id: sells[s].0,
+                });
+                *executed.entry(buys[b].0).or_default() += volume;
+                *executed.entry(sells[s].0).or_default() += volume;
+                buys[b].1 -= volume;
+                sells[s].1 -= volume;
+                remaining -= volume;
+                if buys[b].1.is_zero() {
+                    b += 1;
+                }
+                if sells[s].1.is_zero() {
+                    s += 1;
+                }
+            }
+
+            for (id, volume) in executed {
+                match market_orders.iter_mut().find(|o| o.id == id) {
+                    Some(order) => order.quantity -= volume,
+                    None => self.reduce_resting(id, volume),
+                }
+            }
+            self.last_trade_price = Some(uncross.price);
+            self.reference_price = Some(uncross.price);
+            self.stop_book.trail(uncross.price);
+        }
+
+        market_orders.retain(|o| o.quantity > Decimal::ZERO);
+        self.record_fills(&result.fills);
+        let traded = result
+            .fills
+            .iter()
+            .flat_map(|fill| [fill.taker_id, fill.maker_id]);
+        let unfilled = market_orders.iter().map(|o| o.id);
+        for id in traded.chain(unfilled).collect::<Vec<_>>() {
+            let cancelled = market_orders.iter().any(|o| o.id == id);
+            self.refresh_record(id, false, cancelled);
+        }
+        result.cancelled_orders = market_orders;
+        result.triggered = self.trigger_stops();
+        let fills = AddOrderResult {
+            fills: result.fills.clone(),
+            ..Default::default()
+        };
+        let activated = self.update_groups(&fills);
+        result.triggered.extend(activated);
+        self.reprice_pegged();
+        result
+    }
+
+    // Takes `quantity` off a resting order without touching its priority,
+    // removing it once nothing is left. The reserve goes first and the
+    // displayed quantity only ever shrinks, so an iceberg is never refilled here.
+    fn reduce_resting(&mut self, id: i64, quantity: Decimal) {
+        let Location { side, price } = self.orders[&id];
+        let book_side = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        let level = book_side.get_mut(&price).unwrap();
+        let resting = level.get_mut(id).unwrap();
+        if resting.total_quantity() > quantity {
+            let from_reserve = quantity.min(resting.reserve);
+            resting.reserve -= from_reserve;
+            resting.quantity -= quantity - from_reserve;
+            return;
+        }
+        level.remove(id);
+        if level.is_empty() {
+            book_side.remove(&price);
+        }
+        self.orders.remove(&id);
+    }
+
+    // Moves the book clock forward, expires good-till-date orders that are due
+    // and forgets finished orders older than the retention window.
+    fn advance_time(&mut self, now: Timestamp) -> ExpiryResult {
+        self.time = now;
+        let expired: Vec<i64> = self
+            .live_orders()
+            .filter(|o| o.is_expired(now))
+            .map(|o| o.id)
+            .collect();
+        let expired = self.remove_orders(&expired);
+        let retention = self.retention;
+        self.records.retain(|_, record| {
+            !record.status.is_terminal() || now.saturating_sub(record.updated) <= retention
+        });
+        expired
+    }
+
+    // Expires every DAY order at the end of the trading session.
+    fn end_session(&mut self) -> ExpiryResult {
+        let expired: Vec<i64> = self
+            .live_orders()
+            .filter(|o| o.time_in_force == TimeInForce::Day)
+            .map(|o| o.id)
+            .collect();
+        self.remove_orders(&expired)
+    }
+
+    fn remove_orders(&mut self, ids: &[i64]) -> ExpiryResult {
+        let expired: Vec<Order> = ids.iter().filter_map(|&id| self.take_order(id)).collect();
+        for order in &expired {
+            self.set_status(order.id, OrderStatus::Expired);
+        }
+        let mut activated = Vec::new();
+        for &id in ids {
+            activated.extend(self.settle_order_group(id));
+        }
+        self.reprice_pegged();
+        ExpiryResult { expired, activated }
+    }
+
+    fn update_order(
+        &mut self,
+        id: i64,
+        price: Option<Decimal>,
+        qty: Option<Decimal>,
+    ) -> Result<AmendResult, OrderBookError> {
+        let original = self
+            .find_order(id)
+            .ok_or(OrderBookError::UnknownOrderId(id))?;
+        // Nothing to change: the order and its record are left as they are.
+        if price.is_none_or(|price| price == original.price)
+            && qty.is_none_or(|qty| qty == original.total_quantity())
+        {
+            return Ok(AmendResult {
+                priority_kept: true,
+                fills: Vec::new(),
+            });
+        }
+        let mut order = original.clone();
+        if let Some(price) = price {
+            order.price = price;
+        }
+
+        if let Some(qty) = qty {
+            order.quantity = qty;
+            order.reserve = Decimal::ZERO;
+        }
+
+        // A resting order keeps its place unless the price changes or the
+        // quantity goes up; everything else is a cancel/replace.
+        let reduction = original.total_quantity() - order.total_quantity();
+        if !self.orders.contains_key(&id)
+            || order.price != original.price
+            || reduction < Decimal::ZERO
+        {
+            let result = self.replace_order(id, order)?;
+            return Ok(AmendResult {
+                priority_kept: false,
+                fills: result.fills,
+            });
+        }
+        self.validate(&order)?;
+        self.check_session(&order)?;
+        self.reduce_resting(id, reduction);
+        self.set_status(id, OrderStatus::Replaced);
+        Ok(AmendResult {
+            priority_kept: true,
+            fills: Vec::new(),
+        })
+    }
+
+    // Cancel/replace: the replacement is checked in full before the original is
+    // pulled, and it joins the back of the queue under the same exchange id.
+    // Amends are only accepted while orders are.
+    fn replace_order(
+        &mut self,
+        id: i64,
+        mut replacement: Order,
+    ) -> Result<AddOrderResult, OrderBookError> {
+        let original = self
+            .find_order(id)
+            .ok_or(OrderBookError::UnknownOrderId(id))?;
+        if original.side != replacement.side {
+            return Err(OrderBookError::SideChangeNotAllowed);
+        }
+        self.validate(&replacement)?;
+        let key = (original.owner, original.client_id);
+        let new_key = (replacement.owner, replacement.client_id);
+        if new_key != key {
+            self.check_unique(&replacement)?;
+        }
+        replacement.id = id;
+        self.check_session(&replacement)?;
+        self.take_order(id);
+        if new_key != key {
+            self.client_ids.remove(&key);
+            self.client_ids.insert(new_key, id);
+            let record = self.records.get_mut(&id).unwrap();
+            (record.owner, record.client_id) = new_key;
+        }
+        let result = self.enter_order(replacement);
+        if self.is_live(id) {
+            self.set_status(id, OrderStatus::Replaced);
+        }
+        Ok(result)
+    }
+}
+
+// One order book per listed instrument. Books draw exchange order ids from the
+// engine's sequence, so ids are unique across symbols and cancels and amends
+// are routed by id alone.
+#[derive(Debug)]
+struct MatchingEngine {
+    books: HashMap<String, OrderBook>,
+    order_symbols: HashMap<i64, String>,
+    next_order_id: i64,
+}
+
+impl MatchingEngine {
+    fn new() -> Self {
+        MatchingEngine {
+            books: HashMap::new(),
+            order_symbols: HashMap::new(),
+            next_order_id: 1,
+        }
+    }
+
+    fn list_instrument(&mut self, instrument: Instrument) -> Result<(), OrderBookError> {
+        if self.books.contains_key(&instrument.symbol) {
+            return Err(OrderBookError::DuplicateSymbol);
+        }
+        self.books
+            .insert(instrument.symbol.clone(), OrderBook::new(instrument));
+        Ok(())
+    }
+
+    // Hands back the book with whatever was still working in it.
+    fn delist_instrument(&mut self, symbol: &str) -> Result<OrderBook, OrderBookError> {
+        let book = self
+            .books
+            .remove(symbol)
+            .ok_or(OrderBookError::UnknownSymbol)?;
+        self.order_symbols.retain(|_, s| s != symbol);
+        Ok(book)
+    }
+
+    fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.get(symbol)
+    }
+
+    fn add_order(&mut self, symbol: &str, order: Order) -> Result<AddOrderResult, OrderBookError> {
+        let book = self
+            .books
+            .get_mut(symbol)
+            .ok_or(OrderBookError::UnknownSymbol)?;
+        book.next_order_id = self.next_order_id;
+        let result = book.add_order(order);
+        self.next_order_id = book.next_order_id;
+        let result = result?;
+
+        // Only orders still working are indexed; forget the ones this one finished off.
+        if book.is_live(result.order_id) {
+            self.order_symbols
+                .insert(result.order_id, symbol.to_string());
+        }
+        for done in result.flatten() {
+            let ids = done
+                .fills
+                .iter()
+                .flat_map(|fill| [fill.taker_id, fill.maker_id])
+                .chain(done.self_trade_cancelled.iter().copied())
+                .chain([done.order_id]);
+            for id in ids {
+                if !book.is_live(id) {
+                    self.order_symbols.remove(&id);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn remove_order(&mut self, id: i64) -> Result<CancelResult, OrderBookError> {
+        let removed = self.route(id)?.remove_order(id);
+        self.forget(id, &removed);
+        removed
+    }
+
+    fn update_order(
+        &mut self,
+        id: i64,
+        price: Option<Decimal>,
+        qty: Option<Decimal>,
+    ) -> Result<AmendResult, OrderBookError> {
+        let amended = self.route(id)?.update_order(id, price, qty);
+        self.forget(id, &amended);
+        amended
+    }
+
+    fn route(&mut self, id: i64) -> Result<&mut OrderBook, OrderBookError> {
+        self.order_symbols
+            .get(&id)
+            .and_then(|symbol| self.books.get_mut(symbol))
+            .ok_or(OrderBookError::UnknownOrderId(id))
+    }
+
+    // Orders that expired or were cancelled inside the book are dropped from
+    // the index the next time they are asked for.
+    fn forget<T>(&mut self, id: i64, result: &Result<T, OrderBookError>) {
+        let unknown = matches!(result, Err(OrderBookError::UnknownOrderId(_)));
+        let removed = result.is_ok() && !self.route(id).is_ok_and(|book| book.is_live(id));
+        if unknown || removed {
+            self.order_symbols.remove(&id);
+        }
+    }
+}
+
+fn round_to_lot(quantity: Decimal, lot_size: Decimal) -> Decimal {
+    (quantity / lot_size).floor() * lot_size
+}
+
+fn within_band(side: &Side, price: Decimal, band_limit: Option<Decimal>) -> bool {
+    match (side, band_limit) {
+        (_, None) => true,
+        (Side::Buy, Some(limit)) => price <= limit,
+        (Side::Sell, Some(limit)) => price >= limit,
+    }
+}
+
+fn print_fills(fills: &[Fill]) {
+    println!("## Fills");
+    println!(
+        "{:<10} {:<8} {:<8} {:<8} {:<8}",
+        "MatchedId", "Volume", "Price", "Taker", "Maker"
+    );
+    for fill in fills {
+        println!(
+            "{:<10} {:<8} {:<8} {:<8} {:<8}",
+            fill.matched_id, fill.volume, fill.price, fill.taker_id, fill.maker_id
+        );
+    }
+    println!()
+}
+
+fn print_group(book: &OrderBook, group_id: GroupId) {
+    if let Some(group) = book.group(group_id) {
+        println!(
+            "Group {} {:?}, legs {:?}",
+            group_id, group.state, group.legs
+        );
+    }
+}
+
+fn main() {
+    let mut order_book = OrderBook::new(Instrument::new("ACME"));
+
+    let order1 = Order::limit(1, Side::Buy, dec!(100.0), dec!(10));
+    let order2 = Order::limit(2, Side::Buy, dec!(100.0), dec!(5));
+    let order3 = Order::limit(3, Side::Buy, dec!(101.0), dec!(7));
+
+    order_book.add_order(order1).unwrap();
+    order_book.add_order(order2).unwrap();
+    order_book.add_order(order3).unwrap();
+
+    let order4 = Order::limit(4, Side::Sell, dec!(99.0), dec!(18));
+
+    // We have
+    let result = order_book.add_order(order4).unwrap();
+
+    print_fills(&result.fills);
+    order_book.print_book();
+
+    let order5 = Order::limit(1, Side::Buy, dec!(100.0), dec!(10));
+
+    // Client id 1 is free again once order 1 has filled; the book assigns it exchange id 5.
+    let result = order_book.add_order(order5).unwrap();
+    println!(
+        "Client order {} is order {}",
+        result.client_id, result.order_id
+    );
+    // Raising the quantity sends order 2 behind order 5, cutting it keeps order 5 in front
+    for (id, qty) in [(2, dec!(87)), (5, dec!(8))] {
+        let amend = order_book.update_order(id, None, Some(qty)).unwrap();
+        println!(
+            "Amended order {}, priority kept: {}, fills: {}",
+            id,
+            amend.priority_kept,
+            amend.fills.len()
+        );
+    }
+
+    order_book.print_book();
+
+    // Market order sweeps the bids and cancels whatever is left unfilled
+    let order6 = Order::market(6, Side::Sell, dec!(120));
+    let result = order_book.add_order(order6).unwrap();
+
+    print_fills(&result.fills);
+    println!("Cancelled quantity: {}", result.cancelled_quantity);
+    order_book.print_book();
+
+    // IOC trades what it can and cancels the rest
+    order_book
+        .add_order(Order::limit(7, Side::Sell, dec!(102.0), dec!(5)))
+        .unwrap();
+    let ioc = Order::limit(8, Side::Buy, dec!(102.0), dec!(8))
+        .with_time_in_force(TimeInForce::ImmediateOrCancel);
+    let result = order_book.add_order(ioc).unwrap();
+    print_fills(&result.fills);
+    println!("Cancelled quantity: {}", result.cancelled_quantity);
+
+    // FOK is killed without touching the book when it can't be filled in full
+    order_book
+        .add_order(Order::limit(9, Side::Sell, dec!(103.0), dec!(5)))
+        .unwrap();
+    let fok = Order::limit(10, Side::Buy, dec!(103.0), dec!(6))
+        .with_time_in_force(TimeInForce::FillOrKill);
+    let result = order_book.add_order(fok).unwrap();
+    println!(
+        "FOK fills: {}, cancelled quantity: {}",
+        result.fills.len(),
+        result.cancelled_quantity
+    );
+    let fok = Order::limit(11, Side::Buy, dec!(103.0), dec!(5))
+        .with_time_in_force(TimeInForce::FillOrKill);
+    print_fills(&order_book.add_order(fok).unwrap().fills);
+
+    // DAY orders expire at session end, GTD orders once the clock passes their expiry
+    order_book
+        .add_order(
+            Order::limit(12, Side::Buy, dec!(95.0), dec!(3)).with_time_in_force(TimeInForce::Day),
+        )
+        .unwrap();
+    order_book
+        .add_order(
+            Order::limit(13, Side::Buy, dec!(94.0), dec!(3))
+                .with_time_in_force(TimeInForce::GoodTillDate(1_000)),
+        )
+        .unwrap();
+    order_book
+        .add_order(Order::limit(14, Side::Buy, dec!(93.0), dec!(3)))
+        .unwrap();
+    order_book.print_book();
+    for order in order_book.advance_time(1_000).expired {
+        println!("Expired GTD order {}", order.id);
+    }
+    for order in order_book.end_session().expired {
+        println!("Expired DAY order {}", order.id);
+    }
+    order_book.print_book();
+
+    // Post-only orders never take liquidity: they are either rejected or slid behind the best ask
+    order_book
+        .add_order(Order::limit(15, Side::Sell, dec!(96.0), dec!(4)))
+        .unwrap();
+    let post_only =
+        Order::limit(16, Side::Buy, dec!(96.5), dec!(2)).with_post_only(PostOnly::Reject);
+    let result = order_book.add_order(post_only).unwrap();
+    println!("Post-only action: {:?}", result.post_only_action);
+    let post_only =
+        Order::limit(17, Side::Buy, dec!(96.5), dec!(2)).with_post_only(PostOnly::Reprice);
+    let result = order_book.add_order(post_only).unwrap();
+    println!("Post-only action: {:?}", result.post_only_action);
+    order_book.print_book();
+
+    // A trade at 101 fires the buy stop, whose own fill at 102 fires the stop-limit
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Sell, dec!(101.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(102.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(103.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::stop(4, Side::Buy, dec!(101.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::stop_limit(
+        5,
+        Side::Buy,
+        dec!(102.0),
+        dec!(103.0),
+        dec!(5),
+    ))
+    .unwrap();
+    let result = book
+        .add_order(Order::market(6, Side::Buy, dec!(1)))
+        .unwrap();
+    print_fills(&result.fills);
+    for triggered in &result.triggered {
+        println!("Triggered stop {}", triggered.order_id);
+        print_fills(&triggered.fills);
+    }
+    book.print_book();
+
+    // Only the 5 lot peak of the iceberg is shown; once it trades the refill queues behind order 2
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(
+        Order::limit(1, Side::Sell, dec!(100.0), dec!(20)).with_display_quantity(dec!(5)),
+    )
+    .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(100.0), dec!(5)))
+        .unwrap();
+    book.print_book();
+    let result = book
+        .add_order(Order::market(3, Side::Buy, dec!(7)))
+        .unwrap();
+    print_fills(&result.fills);
+    book.print_book();
+
+    // The hidden bid at the best price is left out of the book and depth, but trades after order 5
+    book.add_order(Order::limit(4, Side::Buy, dec!(99.0), dec!(4)).with_hidden())
+        .unwrap();
+    book.add_order(Order::limit(5, Side::Buy, dec!(99.0), dec!(2)))
+        .unwrap();
+    book.add_order(Order::limit(6, Side::Buy, dec!(98.0), dec!(3)).with_hidden())
+        .unwrap();
+    book.print_book();
+    println!("Bid depth: {:?}", book.depth(&Side::Buy, 5));
+    let result = book
+        .add_order(Order::market(7, Side::Sell, dec!(5)))
+        .unwrap();
+    print_fills(&result.fills);
+
+    // Pegged orders follow the best bid and offer of the non-pegged orders
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Buy, dec!(99.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(101.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::pegged(
+        3,
+        Side::Buy,
+        PegReference::Primary,
+        Decimal::ZERO,
+        dec!(2),
+    ))
+    .unwrap();
+    book.add_order(Order::pegged(
+        4,
+        Side::Sell,
+        PegReference::Midpoint,
+        Decimal::ZERO,
+        dec!(2),
+    ))
+    .unwrap();
+    book.add_order(Order::pegged(
+        5,
+        Side::Buy,
+        PegReference::Market,
+        dec!(-1.5),
+        dec!(2),
+    ))
+    .unwrap();
+    book.print_book();
+    book.add_order(Order::limit(6, Side::Buy, dec!(99.5), dec!(1)))
+        .unwrap();
+    book.print_book();
+    book.remove_order(6).unwrap();
+    book.print_book();
+
+    // The trailing stop starts 2 below the last trade at 100, follows the rally to 103 and fires
+    // once the price falls back through 101
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Buy, dec!(100.0), dec!(1)))
+        .unwrap();
+    book.add_order(Order::market(2, Side::Sell, dec!(1)))
+        .unwrap();
+    book.add_order(Order::trailing_stop(
+        3,
+        Side::Sell,
+        Trail::Amount(dec!(2.0)),
+        dec!(4),
+    ))
+    .unwrap();
+    book.add_order(Order::limit(4, Side::Sell, dec!(103.0), dec!(1)))
+        .unwrap();
+    book.add_order(Order::market(5, Side::Buy, dec!(1)))
+        .unwrap();
+    book.add_order(Order::limit(6, Side::Buy, dec!(100.5), dec!(3)))
+        .unwrap();
+    book.add_order(Order::limit(7, Side::Buy, dec!(99.0), dec!(3)))
+        .unwrap();
+    let result = book
+        .add_order(Order::market(8, Side::Sell, dec!(1)))
+        .unwrap();
+    print_fills(&result.fills);
+    for triggered in &result.triggered {
+        println!("Triggered trailing stop {}", triggered.order_id);
+        print_fills(&triggered.fills);
+    }
+    // Buy stop-limit trailing 1% above the lowest trade price since entry
+    book.add_order(
+        Order::stop_limit(9, Side::Buy, Decimal::ZERO, dec!(105.0), dec!(1))
+            .with_trail(Trail::Percent(dec!(1))),
+    )
+    .unwrap();
+
+    // The all-or-none ask keeps its place while the smaller buy trades with order 2 behind it
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(10)).with_all_or_none())
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(100.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(101.0), dec!(5)))
+        .unwrap();
+    let ioc = Order::limit(4, Side::Buy, dec!(100.0), dec!(6))
+        .with_time_in_force(TimeInForce::ImmediateOrCancel);
+    print_fills(&book.add_order(ioc).unwrap().fills);
+    print_fills(
+        &book
+            .add_order(Order::market(5, Side::Buy, dec!(10)))
+            .unwrap()
+            .fills,
+    );
+
+    // Only 5 is available up to 101, short of the minimum quantity of 6
+    let min_quantity = Order::limit(6, Side::Buy, dec!(101.0), dec!(8))
+        .with_min_quantity(dec!(6))
+        .with_time_in_force(TimeInForce::ImmediateOrCancel);
+    let result = book.add_order(min_quantity).unwrap();
+    println!(
+        "Min quantity fills: {}, cancelled quantity: {}",
+        result.fills.len(),
+        result.cancelled_quantity
+    );
+
+    // The bracket exits only go live once the entry has filled, cancelling the partly filled
+    // entry places them for the 3 bought, and the take-profit fill then cancels the stop-loss
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    let (bracket, _) = book
+        .add_bracket(
+            Order::limit(1, Side::Buy, dec!(100.0), dec!(5)),
+            Order::limit(2, Side::Sell, dec!(105.0), dec!(5)),
+            Order::stop(3, Side::Sell, dec!(95.0), dec!(5)),
+        )
+        .unwrap();
+    print_group(&book, bracket);
+    book.add_order(Order::limit(4, Side::Sell, dec!(100.0), dec!(3)))
+        .unwrap();
+    let cancel = book.remove_order(1).unwrap();
+    println!(
+        "Cancelled order {}, activated {} exit legs",
+        cancel.order.id,
+        cancel.activated.len()
+    );
+    print_group(&book, bracket);
+    book.add_order(Order::market(5, Side::Buy, dec!(1)))
+        .unwrap();
+    print_group(&book, bracket);
+    book.print_book();
+
+    // Cancelling one leg of an OCO pair cancels the other
+    let (oco, _) = book
+        .add_oco(
+            Order::limit(6, Side::Sell, dec!(110.0), dec!(2)),
+            Order::stop(7, Side::Sell, dec!(90.0), dec!(2)),
+        )
+        .unwrap();
+    book.remove_order(7).unwrap();
+    print_group(&book, oco);
+
+    // Pro-rata shares the 10 lots 6/3/0 by resting size: order 3 is under the minimum
+    // allocation and the rounding leftover goes to the oldest order
+    let mut book = OrderBook::new(Instrument::new("ACME")).with_allocation(ProRata {
+        minimum_allocation: dec!(2),
+    });
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(20)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(100.0), dec!(10)))
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(100.0), dec!(3)))
+        .unwrap();
+    print_fills(
+        &book
+            .add_order(Order::market(4, Side::Buy, dec!(10)))
+            .unwrap()
+            .fills,
+    );
+
+    // CME style: the top order is served first, the lead market maker takes 40% of what
+    // is left, the rest goes pro-rata and any rounding remainder in time priority
+    let mut book = OrderBook::new(Instrument::new("ACME")).with_allocation(
+        Pipeline::default()
+            .then(TopOrder { max_quantity: None })
+            .then(LeadMarketMaker { percent: dec!(40) })
+            .then(ProRata {
+                minimum_allocation: dec!(2),
+            })
+            .then(Fifo),
+    );
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(100.0), dec!(20)).with_market_maker())
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(100.0), dec!(30)))
+        .unwrap();
+    book.add_order(Order::limit(4, Side::Sell, dec!(100.0), dec!(10)))
+        .unwrap();
+    print_fills(
+        &book
+            .add_order(Order::market(5, Side::Buy, dec!(40)))
+            .unwrap()
+            .fills,
+    );
+
+    // Orders accumulate during the opening auction and uncross at 100, the price that
+    // executes the most volume
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.start_auction();
+    book.add_order(Order::limit(1, Side::Buy, dec!(101.0), dec!(10)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Buy, dec!(100.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::market(3, Side::Buy, dec!(2)))
+        .unwrap();
+    book.add_order(Order::limit(4, Side::Sell, dec!(99.0), dec!(8)))
+        .unwrap();
+    book.add_order(Order::limit(5, Side::Sell, dec!(100.0), dec!(6)))
+        .unwrap();
+    book.add_order(Order::limit(6, Side::Sell, dec!(102.0), dec!(3)))
+        .unwrap();
+    println!("Indicative uncross: {:?}", book.indicative_uncross());
+    let result = book.close_auction();
+    if let Some(uncross) = result.uncross {
+        println!(
+            "Uncrossed {} at {} with imbalance {}",
+            uncross.volume, uncross.price, uncross.imbalance
+        );
+    }
+    print_fills(&result.fills);
+    println!(
+        "Unfilled market orders: {}, triggered: {}",
+        result.cancelled_orders.len(),
+        result.triggered.len()
+    );
+    book.print_book();
+
+    // A full session: pre-open only takes limit orders, the opening auction uncrosses on the
+    // switch to continuous trading, a halted book rejects orders and closing expires DAY orders
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.transition(SessionState::Closed).unwrap();
+    book.transition(SessionState::PreOpen).unwrap();
+    book.add_order(
+        Order::limit(1, Side::Buy, dec!(100.0), dec!(5)).with_time_in_force(TimeInForce::Day),
+    )
+    .unwrap();
+    let result = book.add_order(Order::market(2, Side::Sell, dec!(2)));
+    println!("Pre-open market order: {:?}", result.err());
+    book.transition(SessionState::Auction).unwrap();
+    book.add_order(Order::market(2, Side::Sell, dec!(2)))
+        .unwrap();
+    let result = book.transition(SessionState::Continuous).unwrap();
+    if let Some(auction) = result.auction {
+        print_fills(&auction.fills);
+    }
+    book.transition(SessionState::Halted).unwrap();
+    let result = book.add_order(Order::limit(4, Side::Sell, dec!(100.0), dec!(1)));
+    println!("Halted order: {:?}", result.err());
+    let result = book.transition(SessionState::Closed).unwrap();
+    for order in result.expired {
+        println!("Expired DAY order {}", order.id);
+    }
+    for activated in result.activated {
+        println!("Activated order {}", activated.order_id);
+    }
+    if let Err(reason) = book.transition(SessionState::Continuous) {
+        println!("Transition rejected: {:?}", reason);
+    }
+    let result = book.add_order(Order::limit(5, Side::Sell, dec!(100.0), dec!(1)));
+    println!("Closed order: {:?}", result.err());
+
+    // The dynamic band allows 2% around the last trade at 100, so the sweep stops before 103,
+    // the book moves into a volatility auction and the residual comes back
+    let mut book = OrderBook::new(Instrument::new("ACME")).with_price_bands(PriceBands {
+        static_percent: Some(dec!(10)),
+        dynamic_percent: Some(dec!(2)),
+    });
+    book.set_reference_price(dec!(100.0)).unwrap();
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(6)))
+        .unwrap();
+    book.add_order(Order::market(2, Side::Buy, dec!(1)))
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(101.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::limit(4, Side::Sell, dec!(103.0), dec!(5)))
+        .unwrap();
+    let result = book
+        .add_order(Order::market(5, Side::Buy, dec!(12)))
+        .unwrap();
+    print_fills(&result.fills);
+    if let Some(residual) = &result.residual {
+        println!(
+            "{:?}: residual {} of order {}, session {:?}",
+            result.reject_reason, residual.quantity, residual.id, book.session
+        );
+    }
+
+    // Owner 7 trades with owner 8 but never with itself: cancel-oldest removes its own
+    // resting ask, decrement shrinks both orders without a fill
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(3)).with_owner(8))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(100.0), dec!(4)).with_owner(7))
+        .unwrap();
+    book.add_order(Order::limit(3, Side::Sell, dec!(101.0), dec!(5)).with_owner(8))
+        .unwrap();
+    let result = book
+        .add_order(
+            Order::market(4, Side::Buy, dec!(6))
+                .with_owner(7)
+                .with_self_trade_prevention(SelfTradePrevention::CancelOldest),
+        )
+        .unwrap();
+    print_fills(&result.fills);
+    println!(
+        "Prevented {}, cancelled {:?}",
+        result.self_trade_prevented, result.self_trade_cancelled
+    );
+    book.add_order(Order::limit(5, Side::Sell, dec!(100.0), dec!(4)).with_owner(7))
+        .unwrap();
+    let result = book
+        .add_order(
+            Order::limit(6, Side::Buy, dec!(100.0), dec!(3))
+                .with_owner(7)
+                .with_self_trade_prevention(SelfTradePrevention::Decrement),
+        )
+        .unwrap();
+    println!(
+        "Prevented {}, cancelled {:?}",
+        result.self_trade_prevented, result.self_trade_cancelled
+    );
+    let result = book
+        .add_order(
+            Order::limit(7, Side::Buy, dec!(100.0), dec!(2))
+                .with_owner(7)
+                .with_self_trade_prevention(SelfTradePrevention::CancelBoth),
+        )
+        .unwrap();
+    println!(
+        "Prevented {}, cancelled {:?}",
+        result.self_trade_prevented, result.self_trade_cancelled
+    );
+    book.print_book();
+
+    // Fractional sizes in lots of 0.001 on a half dollar tick: odd lots, off-tick prices and
+    // orders outside the instrument limits are invalid
+    let instrument = Instrument::new("BTC-USD")
+        .with_tick_size(dec!(0.5), 1)
+        .with_lot_size(dec!(0.001))
+        .with_quantity_limits(None, Some(dec!(10)))
+        .with_price_limits(Some(dec!(1000)), Some(dec!(1000000)));
+    let mut book = OrderBook::new(instrument);
+    book.add_order(Order::limit(1, Side::Sell, dec!(64000), dec!(0.25)))
+        .unwrap();
+    book.add_order(Order::limit(2, Side::Sell, dec!(64010.5), dec!(1.5)))
+        .unwrap();
+    let result = book
+        .add_order(Order::market(3, Side::Buy, dec!(0.4)))
+        .unwrap();
+    print_fills(&result.fills);
+    let rejects = [
+        book.add_order(Order::market(4, Side::Buy, dec!(0.40075)))
+            .err(),
+        book.add_order(Order::limit(4, Side::Buy, dec!(63990), dec!(0.0004)))
+            .err(),
+        book.add_order(Order::limit(4, Side::Buy, dec!(63990.2), dec!(1)))
+            .err(),
+        book.add_order(Order::limit(4, Side::Buy, dec!(63990), dec!(12)))
+            .err(),
+        book.add_order(Order::limit(4, Side::Buy, dec!(999.5), dec!(1)))
+            .err(),
+    ];
+    for reject in rejects.into_iter().flatten() {
+        println!("Rejected: {:?}", reject);
+    }
+    book.add_order(Order::limit(4, Side::Buy, dec!(63990), dec!(0.002)))
+        .unwrap();
+    book.print_book();
+
+    // Invalid requests are turned away without touching the book
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(Order::limit(1, Side::Buy, dec!(100.0), dec!(5)))
+        .unwrap();
+    let rejects = [
+        book.add_order(Order::limit(2, Side::Buy, dec!(100.0), Decimal::ZERO))
+            .err(),
+        book.add_order(Order::limit(3, Side::Buy, dec!(-1.0), dec!(5)))
+            .err(),
+        book.add_order(Order::stop(4, Side::Sell, Decimal::ZERO, dec!(5)))
+            .err(),
+        book.add_order(Order::market(5, Side::Sell, dec!(5)).with_post_only(PostOnly::Reprice))
+            .err(),
+        book.add_order(Order::limit(1, Side::Buy, dec!(99.0), dec!(5)))
+            .err(),
+        book.remove_order(9).err(),
+        book.update_order(9, Some(dec!(101.0)), None).err(),
+        book.replace_order(1, Order::limit(1, Side::Sell, dec!(100.0), dec!(5)))
+            .err(),
+    ];
+    for reject in rejects.into_iter().flatten() {
+        println!("Rejected: {:?}", reject);
+    }
+    book.print_book();
+
+    // The engine routes orders by symbol and cancels and amends by exchange id alone
+    let mut engine = MatchingEngine::new();
+    engine.list_instrument(Instrument::new("ACME")).unwrap();
+    engine
+        .list_instrument(Instrument::new("GLOBEX").with_tick_size(dec!(0.05), 2))
+        .unwrap();
+    engine
+        .add_order("ACME", Order::limit(1, Side::Buy, dec!(100.0), dec!(5)))
+        .unwrap();
+    engine
+        .add_order("GLOBEX", Order::limit(1, Side::Sell, dec!(20.05), dec!(10)))
+        .unwrap();
+    let result = engine
+        .add_order("GLOBEX", Order::market(2, Side::Buy, dec!(4)))
+        .unwrap();
+    print_fills(&result.fills);
+    engine.update_order(2, Some(dec!(20.10)), None).unwrap();
+    engine.remove_order(1).unwrap();
+    let rejects = [
+        engine
+            .add_order("INITECH", Order::limit(1, Side::Buy, dec!(5.0), dec!(1)))
+            .err(),
+        engine.list_instrument(Instrument::new("ACME")).err(),
+        engine.remove_order(1).err(),
+    ];
+    for reject in rejects.into_iter().flatten() {
+        println!("Rejected: {:?}", reject);
+    }
+    if let Some(book) = engine.book("ACME") {
+        book.print_book();
+    }
+    engine.delist_instrument("GLOBEX").unwrap().print_book();
+    println!("After delisting: {:?}", engine.remove_order(2).err());
+
+    // Cancels unlink orders from the middle of the queue and new orders reuse the freed slots
+    // while joining the back of the queue, leaving it as 1, 4, 6, 7
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    for id in 1..=5 {
+        book.add_order(Order::limit(id, Side::Sell, dec!(100.0), dec!(1)))
+            .unwrap();
+    }
+    for id in [2, 3, 5] {
+        book.remove_order(id).unwrap();
+    }
+    for id in 6..=7 {
+        book.add_order(Order::limit(id, Side::Sell, dec!(100.0), dec!(1)))
+            .unwrap();
+    }
+    book.print_book();
+
+    // Queries read the order in its level: after a fill of 3 the iceberg shows 2 and has 7 left
+    let mut book = OrderBook::new(Instrument::new("ACME"));
+    book.add_order(
+        Order::limit(1, Side::Sell, dec!(100.0), dec!(10)).with_display_quantity(dec!(5)),
+    )
+    .unwrap();
+    book.add_order(Order::market(2, Side::Buy, dec!(3)))
+        .unwrap();
+    if let Some(order) = book.find_order(1) {
+        println!(
+            "Order 1 shows {}, remaining {:?}",
+            order.quantity,
+            book.remaining_quantity(1)
+        );
+    }
+
+    // Order status through its lifecycle; finished orders stay queryable for 100 time units, so
+    // by time 115 only order 3, filled at time 10, has been forgotten
+    let mut book = OrderBook::new(Instrument::new("ACME")).with_retention(100);
+    book.add_order(Order::limit(1, Side::Sell, dec!(100.0), dec!(10)))
+        .unwrap();
+    book.advance_time(10);
+    book.add_order(Order::limit(2, Side::Sell, dec!(101.0), dec!(5)))
+        .unwrap();
+    book.add_order(Order::market(3, Side::Buy, dec!(7)))
+        .unwrap();
+    book.update_order(1, None, Some(dec!(2))).unwrap();
+    book.advance_time(20);
+    book.remove_order(2).unwrap();
+    book.add_order(
+        Order::limit(4, Side::Buy, dec!(100.0), dec!(5)).with_post_only(PostOnly::Reject),
+    )
+    .unwrap();
+    let print_orders = |book: &OrderBook| {
+        for id in 1..=4 {
+            if let Some(order) = book.get_order(id) {
+                println!(
+                    "Order {} (client {}): {:?}, filled {} of {} at {:?}, remaining {}, created {}, updated {}",
+                    id,
+                    order.client_id,
+                    order.status,
+                    order.filled_quantity,
+                    order.original_quantity,
+                    order.average_price(),
+                    order.remaining_quantity,
+                    order.created,
+                    order.updated
+                );
+            }
+        }
+    };
+    print_orders(&book);
+    book.advance_time(115);
+    print_orders(&book);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (maker, volume) pairs in the order the fills were emitted.
+    fn trades(fills: &[Fill]) -> Vec<(i64, Decimal)> {
+        fills
+            .iter()
+            .map(|fill| (fill.maker_id, fill.volume))
+            .collect()
+    }
+
+    fn book_with(allocation: impl AllocationStrategy + 'static) -> OrderBook {
+        OrderBook::new(Instrument::new("ACME")).with_allocation(allocation)
+    }
+
+    #[test]
+    fn fifo_fills_in_time_priority() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        let result = book
+            .add_order(Order::market(3, Side::Buy, dec!(7)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(5)), (2, dec!(2))]);
+    }
+
+    #[test]
+    fn pro_rata_drops_small_shares_and_hands_out_the_remainder_in_time_priority() {
+        let mut book = book_with(ProRata {
+            minimum_allocation: dec!(2),
+        });
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(10)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(2)))
+            .unwrap();
+        book.add_order(Order::limit(3, Side::Sell, dec!(100), dec!(28)))
+            .unwrap();
+        // Shares of 10 are 2.5, 0.5 and 7, so 2, 0 and 7; order 1 takes the leftover lot.
+        let result = book
+            .add_order(Order::market(4, Side::Buy, dec!(10)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(3)), (3, dec!(7))]);
+    }
+
+    #[test]
+    fn pipeline_reproduces_top_order_lead_market_maker_and_pro_rata() {
+        let mut book = book_with(
+            Pipeline::default()
+                .then(TopOrder { max_quantity: None })
+                .then(LeadMarketMaker { percent: dec!(40) })
+                .then(ProRata {
+                    minimum_allocation: dec!(2),
+                })
+                .then(Fifo),
+        );
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(20)).with_market_maker())
+            .unwrap();
+        book.add_order(Order::limit(3, Side::Sell, dec!(100), dec!(30)))
+            .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(100), dec!(10)))
+            .unwrap();
+        // Top order 5, then 40% of 35 to the market maker, then 21 pro-rata over 6, 30 and 10.
+        let result = book
+            .add_order(Order::market(5, Side::Buy, dec!(40)))
+            .unwrap();
+        assert_eq!(
+            trades(&result.fills),
+            [
+                (1, dec!(5)),
+                (2, dec!(14)),
+                (2, dec!(4)),
+                (3, dec!(13)),
+                (4, dec!(4)),
+            ]
+        );
+        assert_eq!(book.remaining_quantity(2), Some(dec!(2)));
+    }
+
+    #[test]
+    fn pipeline_top_order_cap_and_fifo_remainder() {
+        let mut book = book_with(
+            Pipeline::default()
+                .then(TopOrder {
+                    max_quantity: Some(dec!(3)),
+                })
+                .then(Fifo),
+        );
+        book.add_order(Order::limit(1, Side::Buy, dec!(100), dec!(10)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Buy, dec!(100), dec!(10)))
+            .unwrap();
+        let result = book
+            .add_order(Order::limit(3, Side::Sell, dec!(100), dec!(15)))
+            .unwrap();
+        assert_eq!(
+            trades(&result.fills),
+            [(1, dec!(3)), (1, dec!(7)), (2, dec!(5))]
+        );
+    }
+
+    #[test]
+    fn fill_or_kill_does_not_count_own_orders() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(3)).with_owner(7))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(3)).with_owner(8))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(3, Side::Buy, dec!(100), dec!(5))
+                    .with_owner(7)
+                    .with_self_trade_prevention(SelfTradePrevention::CancelOldest)
+                    .with_time_in_force(TimeInForce::FillOrKill),
+            )
+            .unwrap();
+        assert!(result.fills.is_empty());
+        assert_eq!(result.cancelled_quantity, dec!(5));
+        assert_eq!(book.remaining_quantity(1), Some(dec!(3)));
+        assert_eq!(book.remaining_quantity(2), Some(dec!(3)));
+    }
+
+    #[test]
+    fn fill_or_kill_counts_decremented_quantity_as_unfilled() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(3)).with_owner(7))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(5)).with_owner(8))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(3, Side::Buy, dec!(100), dec!(5))
+                    .with_owner(7)
+                    .with_self_trade_prevention(SelfTradePrevention::Decrement)
+                    .with_time_in_force(TimeInForce::FillOrKill),
+            )
+            .unwrap();
+        assert!(result.fills.is_empty());
+        assert_eq!(result.cancelled_quantity, dec!(5));
+    }
+
+    #[test]
+    fn all_or_none_rests_when_only_own_orders_would_fill_it() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(3)).with_owner(8))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(3)).with_owner(7))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(3, Side::Buy, dec!(100), dec!(5))
+                    .with_owner(7)
+                    .with_self_trade_prevention(SelfTradePrevention::CancelNewest)
+                    .with_all_or_none(),
+            )
+            .unwrap();
+        assert!(result.fills.is_empty());
+        assert_eq!(book.remaining_quantity(3), Some(dec!(5)));
+    }
+
+    #[test]
+    fn cancelling_a_partly_filled_bracket_entry_reports_exit_leg_fills() {
+        let mut book = book_with(Fifo);
+        book.add_bracket(
+            Order::limit(1, Side::Buy, dec!(100), dec!(5)),
+            Order::limit(2, Side::Sell, dec!(103), dec!(5)),
+            Order::stop(3, Side::Sell, dec!(95), dec!(5)),
+        )
+        .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(100), dec!(3)))
+            .unwrap();
+        let bid = book
+            .add_order(Order::limit(5, Side::Buy, dec!(104), dec!(3)))
+            .unwrap();
+        let cancel = book.remove_order(1).unwrap();
+        assert_eq!(cancel.order.id, 1);
+        let fills: Vec<(i64, Decimal)> = cancel
+            .activated
+            .iter()
+            .flat_map(|result| trades(&result.fills))
+            .collect();
+        assert_eq!(fills, [(bid.order_id, dec!(3))]);
+        assert_eq!(book.get_order(2).unwrap().status, OrderStatus::Filled);
+        assert_eq!(book.get_order(3).unwrap().status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn closing_auction_does_not_trade_triggered_stops() {
+        let mut book = book_with(Fifo);
+        let ask = book
+            .add_order(Order::limit(1, Side::Sell, dec!(101), dec!(5)))
+            .unwrap();
+        let stop = book
+            .add_order(Order::stop(2, Side::Buy, dec!(100), dec!(2)))
+            .unwrap();
+        book.transition(SessionState::Auction).unwrap();
+        book.add_order(Order::limit(3, Side::Buy, dec!(100), dec!(1)))
+            .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(100), dec!(1)))
+            .unwrap();
+        let result = book.transition(SessionState::Closed).unwrap();
+        let auction = result.auction.unwrap();
+        assert_eq!(auction.fills.len(), 1);
+        assert!(auction.triggered.is_empty());
+        assert_eq!(book.remaining_quantity(ask.order_id), Some(dec!(5)));
+        assert!(book.is_live(stop.order_id));
+    }
+
+    #[test]
+    fn halted_book_does_not_match_activated_legs() {
+        let mut book = book_with(Fifo);
+        book.add_bracket(
+            Order::limit(1, Side::Buy, dec!(100), dec!(5)),
+            Order::limit(2, Side::Sell, dec!(103), dec!(5)),
+            Order::stop(3, Side::Sell, dec!(95), dec!(5)),
+        )
+        .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(100), dec!(3)))
+            .unwrap();
+        let bid = book
+            .add_order(Order::limit(5, Side::Buy, dec!(104), dec!(3)))
+            .unwrap();
+        book.transition(SessionState::Halted).unwrap();
+        let cancel = book.remove_order(1).unwrap();
+        assert!(cancel
+            .activated
+            .iter()
+            .all(|result| result.fills.is_empty()));
+        assert_eq!(book.remaining_quantity(bid.order_id), Some(dec!(3)));
+        assert_eq!(book.remaining_quantity(2), Some(dec!(3)));
+    }
+
+    #[test]
+    fn records_outlive_time_going_backwards() {
+        let mut book = book_with(Fifo).with_retention(10);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(1)))
+            .unwrap();
+        book.advance_time(20);
+        book.remove_order(1).unwrap();
+        book.advance_time(5);
+        assert_eq!(book.get_order(1).unwrap().status, OrderStatus::Cancelled);
+        book.advance_time(31);
+        assert!(book.get_order(1).is_none());
+    }
+
+    #[test]
+    fn maker_decremented_to_nothing_is_cancelled() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(3)).with_owner(7))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(2, Side::Buy, dec!(100), dec!(5))
+                    .with_owner(7)
+                    .with_self_trade_prevention(SelfTradePrevention::Decrement),
+            )
+            .unwrap();
+        assert_eq!(result.self_trade_cancelled, [1]);
+        assert_eq!(book.get_order(1).unwrap().status, OrderStatus::Cancelled);
+        assert_eq!(book.remaining_quantity(2), Some(dec!(2)));
+    }
+
+    #[test]
+    fn oco_leg_never_placed_is_cancelled() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(2)))
+            .unwrap();
+        let (_, results) = book
+            .add_oco(
+                Order::limit(2, Side::Buy, dec!(100), dec!(2)),
+                Order::stop(3, Side::Buy, dec!(110), dec!(2)),
+            )
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(book.get_order(2).unwrap().status, OrderStatus::Filled);
+        assert_eq!(book.get_order(3).unwrap().status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn empty_amend_leaves_the_order_alone() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(2)))
+            .unwrap();
+        book.advance_time(10);
+        let amend = book.update_order(1, None, None).unwrap();
+        assert!(amend.priority_kept);
+        let record = book.get_order(1).unwrap();
+        assert_eq!(record.status, OrderStatus::New);
+        assert_eq!(record.updated, 0);
+    }
+
+    #[test]
+    fn fifo_passes_over_all_or_none_orders_it_cannot_fill() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)).with_all_or_none())
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(2)))
+            .unwrap();
+        book.add_order(Order::limit(3, Side::Sell, dec!(100), dec!(3)))
+            .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(100), dec!(3)))
+            .unwrap();
+        let result = book
+            .add_order(Order::market(5, Side::Buy, dec!(4)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(2, dec!(2)), (3, dec!(2))]);
+        let result = book
+            .add_order(Order::market(6, Side::Buy, dec!(6)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(5)), (3, dec!(1))]);
+    }
+
+    #[test]
+    fn trailed_stop_is_cancelled_by_id() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Buy, dec!(100), dec!(1)))
+            .unwrap();
+        book.add_order(Order::market(2, Side::Sell, dec!(1)))
+            .unwrap();
+        let stop = book
+            .add_order(Order::trailing_stop(
+                3,
+                Side::Sell,
+                Trail::Amount(dec!(2)),
+                dec!(4),
+            ))
+            .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(103), dec!(1)))
+            .unwrap();
+        book.add_order(Order::market(5, Side::Buy, dec!(1)))
+            .unwrap();
+        assert_eq!(
+            book.find_order(stop.order_id).unwrap().trigger_price(),
+            Some(dec!(101))
+        );
+        let cancel = book.remove_order(stop.order_id).unwrap();
+        assert_eq!(cancel.order.id, stop.order_id);
+        assert!(!book.is_live(stop.order_id));
+        assert_eq!(book.live_orders().count(), 0);
+    }
+
+    #[test]
+    fn client_ids_are_reusable_once_the_order_is_done() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(2)).with_owner(7))
+            .unwrap();
+        assert!(matches!(
+            book.add_order(Order::limit(1, Side::Sell, dec!(101), dec!(2)).with_owner(7)),
+            Err(OrderBookError::DuplicateOrderId(1))
+        ));
+        book.add_order(Order::limit(1, Side::Sell, dec!(101), dec!(2)).with_owner(8))
+            .unwrap();
+        book.add_order(Order::market(2, Side::Buy, dec!(2)))
+            .unwrap();
+        book.add_order(Order::limit(1, Side::Sell, dec!(102), dec!(2)).with_owner(7))
+            .unwrap();
+        book.remove_order(4).unwrap();
+        book.add_order(
+            Order::limit(1, Side::Sell, dec!(102), dec!(2))
+                .with_owner(7)
+                .with_time_in_force(TimeInForce::GoodTillDate(10)),
+        )
+        .unwrap();
+        book.advance_time(10);
+        book.add_order(Order::limit(1, Side::Sell, dec!(103), dec!(2)).with_owner(7))
+            .unwrap();
+    }
+
+    #[test]
+    fn pending_bracket_legs_and_replacements_hold_their_client_ids() {
+        let mut book = book_with(Fifo);
+        book.add_bracket(
+            Order::limit(1, Side::Buy, dec!(100), dec!(5)),
+            Order::limit(2, Side::Sell, dec!(103), dec!(5)),
+            Order::stop(3, Side::Sell, dec!(95), dec!(5)),
+        )
+        .unwrap();
+        assert!(book
+            .add_order(Order::limit(3, Side::Sell, dec!(103), dec!(1)))
+            .is_err());
+        let ask = book
+            .add_order(Order::limit(4, Side::Sell, dec!(104), dec!(1)))
+            .unwrap();
+        book.replace_order(
+            ask.order_id,
+            Order::limit(5, Side::Sell, dec!(105), dec!(1)),
+        )
+        .unwrap();
+        assert_eq!(book.get_order(ask.order_id).unwrap().client_id, 5);
+        book.add_order(Order::limit(4, Side::Sell, dec!(104), dec!(1)))
+            .unwrap();
+        assert!(book
+            .add_order(Order::limit(5, Side::Sell, dec!(105), dec!(1)))
+            .is_err());
+    }
+
+    #[test]
+    fn pegged_prices_snap_to_the_tick_on_the_passive_side() {
+        let mut book = OrderBook::new(Instrument::new("ACME").with_tick_size(dec!(0.05), 2));
+        book.add_order(Order::limit(1, Side::Buy, dec!(99), dec!(5)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(101.05), dec!(5)))
+            .unwrap();
+        let buy = book
+            .add_order(Order::pegged(
+                3,
+                Side::Buy,
+                PegReference::Midpoint,
+                Decimal::ZERO,
+                dec!(1),
+            ))
+            .unwrap();
+        let sell = book
+            .add_order(Order::pegged(
+                4,
+                Side::Sell,
+                PegReference::Midpoint,
+                Decimal::ZERO,
+                dec!(1),
+            ))
+            .unwrap();
+        assert_eq!(book.find_order(buy.order_id).unwrap().price, dec!(100));
+        assert_eq!(book.find_order(sell.order_id).unwrap().price, dec!(100.05));
+    }
+
+    #[test]
+    fn hidden_orders_do_not_move_pegs() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Buy, dec!(99), dec!(5)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Buy, dec!(99.5), dec!(5)).with_hidden())
+            .unwrap();
+        let peg = book
+            .add_order(Order::pegged(
+                3,
+                Side::Buy,
+                PegReference::Primary,
+                Decimal::ZERO,
+                dec!(1),
+            ))
+            .unwrap();
+        assert_eq!(book.find_order(peg.order_id).unwrap().price, dec!(99));
+    }
+
+    #[test]
+    fn amending_an_iceberg_down_does_not_refill_its_peak() {
+        let mut book = book_with(Fifo);
+        book.add_order(
+            Order::limit(1, Side::Sell, dec!(100), dec!(10)).with_display_quantity(dec!(2)),
+        )
+        .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        let amend = book.update_order(1, None, Some(dec!(5))).unwrap();
+        assert!(amend.priority_kept);
+        let iceberg = book.find_order(1).unwrap();
+        assert_eq!((iceberg.quantity, iceberg.reserve), (dec!(2), dec!(3)));
+        let result = book
+            .add_order(Order::market(3, Side::Buy, dec!(5)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(2)), (2, dec!(3))]);
+        book.update_order(1, None, Some(dec!(1))).unwrap();
+        let iceberg = book.find_order(1).unwrap();
+        assert_eq!((iceberg.quantity, iceberg.reserve), (dec!(1), dec!(0)));
+    }
+
+    #[test]
+    fn top_order_cap_applies_once_per_level() {
+        let mut book = book_with(TopOrder {
+            max_quantity: Some(dec!(3)),
+        });
+        book.add_order(Order::limit(1, Side::Buy, dec!(100), dec!(10)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Buy, dec!(100), dec!(10)))
+            .unwrap();
+        let result = book
+            .add_order(Order::market(3, Side::Sell, dec!(15)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(3))]);
+        assert_eq!(result.cancelled_quantity, dec!(12));
+    }
+
+    #[test]
+    fn pipeline_steps_serve_each_order_once_per_level() {
+        let mut book = book_with(
+            Pipeline::default()
+                .then(TopOrder {
+                    max_quantity: Some(dec!(2)),
+                })
+                .then(LeadMarketMaker { percent: dec!(50) }),
+        );
+        book.add_order(Order::limit(1, Side::Buy, dec!(100), dec!(10)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Buy, dec!(100), dec!(10)).with_market_maker())
+            .unwrap();
+        let result = book
+            .add_order(Order::market(3, Side::Sell, dec!(10)))
+            .unwrap();
+        assert_eq!(trades(&result.fills), [(1, dec!(2)), (2, dec!(4))]);
+    }
+
+    #[test]
+    fn refilled_iceberg_slices_trade_again_in_time_priority() {
+        let mut book = book_with(ProRata {
+            minimum_allocation: dec!(1),
+        });
+        book.add_order(
+            Order::limit(1, Side::Sell, dec!(100), dec!(6)).with_display_quantity(dec!(2)),
+        )
+        .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(4)))
+            .unwrap();
+        let result = book
+            .add_order(Order::market(3, Side::Buy, dec!(8)))
+            .unwrap();
+        assert_eq!(
+            trades(&result.fills),
+            [(1, dec!(2)), (2, dec!(4)), (1, dec!(2))]
+        );
+        assert_eq!(book.remaining_quantity(1), Some(dec!(2)));
+    }
+
+    #[test]
+    fn fill_or_kill_checks_liquidity_with_the_book_allocation() {
+        let mut book = book_with(ProRata {
+            minimum_allocation: dec!(1),
+        });
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)).with_all_or_none())
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(100), dec!(10)))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(3, Side::Buy, dec!(100), dec!(12))
+                    .with_time_in_force(TimeInForce::FillOrKill),
+            )
+            .unwrap();
+        assert!(result.fills.is_empty());
+        assert_eq!(result.cancelled_quantity, dec!(12));
+        let result = book
+            .add_order(
+                Order::limit(4, Side::Buy, dec!(100), dec!(12))
+                    .with_min_quantity(dec!(11))
+                    .with_time_in_force(TimeInForce::ImmediateOrCancel),
+            )
+            .unwrap();
+        assert!(result.fills.is_empty());
+    }
+
+    #[test]
+    fn fill_or_kill_counts_iceberg_reserve() {
+        let mut book = book_with(Fifo);
+        book.add_order(
+            Order::limit(1, Side::Sell, dec!(100), dec!(6)).with_display_quantity(dec!(2)),
+        )
+        .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(2, Side::Buy, dec!(100), dec!(5))
+                    .with_time_in_force(TimeInForce::FillOrKill),
+            )
+            .unwrap();
+        assert_eq!(
+            trades(&result.fills),
+            [(1, dec!(2)), (1, dec!(2)), (1, dec!(1))]
+        );
+    }
+
+    #[test]
+    fn post_only_is_limited_to_limit_orders() {
+        let mut book = book_with(Fifo);
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        for order in [
+            Order::market(2, Side::Buy, dec!(2)),
+            Order::stop(3, Side::Buy, dec!(101), dec!(2)),
+        ] {
+            assert!(matches!(
+                book.add_order(order.with_post_only(PostOnly::Reprice)),
+                Err(OrderBookError::PostOnlyNotAllowed)
+            ));
+        }
+        assert_eq!(book.remaining_quantity(1), Some(dec!(5)));
+    }
+
+    #[test]
+    fn post_only_repriced_off_the_instrument_is_rejected() {
+        let mut book = OrderBook::new(
+            Instrument::new("ACME").with_price_limits(Some(dec!(0.01)), Some(dec!(200))),
+        );
+        book.add_order(Order::limit(1, Side::Sell, dec!(0.01), dec!(5)))
+            .unwrap();
+        let result = book
+            .add_order(
+                Order::limit(2, Side::Buy, dec!(0.02), dec!(5)).with_post_only(PostOnly::Reprice),
+            )
+            .unwrap();
+        assert_eq!(result.post_only_action, Some(PostOnlyAction::Rejected));
+        assert_eq!(result.cancelled_quantity, dec!(5));
+        assert!(!book.is_live(result.order_id));
+        assert_eq!(
+            book.get_order(result.order_id).unwrap().status,
+            OrderStatus::Rejected
+        );
+    }
+
+    #[test]
+    fn uncross_leaves_out_all_or_none_orders_it_cannot_fill() {
+        let mut book = book_with(Fifo);
+        book.transition(SessionState::Auction).unwrap();
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(10)).with_all_or_none())
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Buy, dec!(100), dec!(4)))
+            .unwrap();
+        assert_eq!(book.indicative_uncross(), None);
+        book.add_order(Order::limit(3, Side::Buy, dec!(101), dec!(5)).with_all_or_none())
+            .unwrap();
+        book.add_order(Order::limit(4, Side::Sell, dec!(99), dec!(5)))
+            .unwrap();
+        let result = book.transition(SessionState::Continuous).unwrap();
+        let auction = result.auction.unwrap();
+        // At 101 the all-or-none buy fills in full against order 4.
+        let uncross = auction.uncross.unwrap();
+        assert_eq!((uncross.price, uncross.volume), (dec!(101), dec!(5)));
+        assert_eq!(
+            auction
+                .fills
+                .iter()
+                .map(|fill| (fill.taker_id, fill.maker_id, fill.volume))
+                .collect::<Vec<_>>(),
+            [(3, 4, dec!(5))]
+        );
+        assert_eq!(book.remaining_quantity(1), Some(dec!(10)));
+        assert_eq!(book.remaining_quantity(2), Some(dec!(4)));
+        assert!(!book.is_live(4));
+    }
+
+    #[test]
+    fn engine_only_indexes_working_orders() {
+        let mut engine = MatchingEngine::new();
+        engine.list_instrument(Instrument::new("ACME")).unwrap();
+        engine
+            .add_order("ACME", Order::limit(1, Side::Sell, dec!(100), dec!(1)))
+            .unwrap();
+        engine
+            .add_order("ACME", Order::stop(2, Side::Buy, dec!(100), dec!(5)))
+            .unwrap();
+        for client_id in 3..13 {
+            engine
+                .add_order(
+                    "ACME",
+                    Order::limit(client_id, Side::Buy, dec!(99), dec!(1))
+                        .with_time_in_force(TimeInForce::ImmediateOrCancel),
+                )
+                .unwrap();
+        }
+        assert_eq!(engine.order_symbols.len(), 2);
+        // The market buy fills order 1 and triggers stop 2, which finds nothing left to buy.
+        engine
+            .add_order("ACME", Order::market(13, Side::Buy, dec!(1)))
+            .unwrap();
+        assert!(engine.order_symbols.is_empty());
+    }
+
+    #[test]
+    fn order_rejected_after_trading_is_cancelled() {
+        let mut book = book_with(Fifo).with_price_bands(PriceBands {
+            static_percent: Some(dec!(2)),
+            dynamic_percent: None,
+        });
+        book.set_reference_price(dec!(100)).unwrap();
+        book.add_order(Order::limit(1, Side::Sell, dec!(100), dec!(5)))
+            .unwrap();
+        book.add_order(Order::limit(2, Side::Sell, dec!(103), dec!(5)))
+            .unwrap();
+        let result = book
+            .add_order(Order::market(3, Side::Buy, dec!(8)))
+            .unwrap();
+        assert_eq!(
+            result.reject_reason,
+            Some(RejectReason::PriceBandBreached(dec!(103)))
+        );
+        let record = book.get_order(result.order_id).unwrap();
+        assert_eq!(record.status, OrderStatus::Cancelled);
+        assert_eq!(record.filled_quantity, dec!(5));
+    }
+
+    #[test]
+    fn only_trailing_stops_are_tracked_for_trailing() {
+        let mut stops = StopBook::default();
+        let mut fixed = Order::stop(1, Side::Sell, dec!(95), dec!(1));
+        fixed.id = 1;
+        let mut trailing = Order::trailing_stop(2, Side::Sell, Trail::Amount(dec!(2)), dec!(1));
+        trailing.id = 2;
+        trailing.set_trigger_price(dec!(98));
+        stops.insert(fixed);
+        stops.insert(trailing);
+        assert_eq!(stops.trailing.len(), 1);
+        stops.trail(dec!(103));
+        assert_eq!(stops.get(2).unwrap().trigger_price(), Some(dec!(101)));
+        assert_eq!(stops.get(1).unwrap().trigger_price(), Some(dec!(95)));
+        assert_eq!(stops.pop_triggered(dec!(100)).map(|o| o.id), Some(2));
+        assert!(stops.trailing.is_empty());
+    }
 }